@@ -0,0 +1,392 @@
+use anyhow::{anyhow, Result};
+
+use axum::http::header;
+
+use http::HeaderMap;
+use http::HeaderValue;
+
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+
+use std::collections::BTreeMap;
+
+const CLIENT_TAGS_HEADER: &str = "x-trino-client-tags";
+const SESSION_HEADER: &str = "x-trino-session";
+const DEFAULT_BLOCK_MESSAGE: &str = "Request blocked";
+
+/// The structs in this section carry the parameters of each action from the config file.
+
+#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema)]
+pub struct BlockAction {
+    /// Message returned to the client instead of the default one.
+    pub message: Option<String>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema)]
+pub struct HeaderAction {
+    pub name: String,
+    pub value: String,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema)]
+pub struct RemoveHeaderAction {
+    pub name: String,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema)]
+pub struct ClientTagsAction {
+    pub tags: Vec<String>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema)]
+pub struct SessionPropertiesAction {
+    pub properties: BTreeMap<String, String>,
+    /// Replace session properties the client already set, instead of keeping them.
+    #[serde(default, rename = "override")]
+    pub override_existing: bool,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema)]
+pub struct NamedAction {
+    /// The name of a chain in the `actions` section of the config.
+    pub name: String,
+}
+
+/// Actions that can be attached to a rule, applied in the order they are
+/// listed in the rule's `actions` chain.
+#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema)]
+#[serde(tag = "type")]
+pub enum ActionType {
+    Block(BlockAction),
+    /// Shorthand for `AddClientTags` with the `lowprio` tag.
+    InjectHeader,
+    /// Set a header, replacing any value the client sent.
+    SetHeader(HeaderAction),
+    /// Append to a comma separated header such as `X-Trino-Session`.
+    AppendHeader(HeaderAction),
+    RemoveHeader(RemoveHeaderAction),
+    AddClientTags(ClientTagsAction),
+    /// Merge properties such as `query_max_run_time` into `X-Trino-Session`.
+    SetSessionProperties(SessionPropertiesAction),
+    /// Run a named chain of actions, resolved when the config is loaded.
+    Named(NamedAction),
+}
+
+/// An action as written in a rule: tagged with its `type`, or the bare name
+/// of a `Block` or `InjectHeader` action as the legacy `action` key took it,
+/// e.g. `"action": "Block"`.
+#[derive(Debug, Deserialize, JsonSchema)]
+#[serde(untagged)]
+pub enum ActionConfig {
+    Tagged(ActionType),
+    Legacy(LegacyAction),
+}
+
+#[derive(Debug, Deserialize, JsonSchema)]
+pub enum LegacyAction {
+    Block,
+    InjectHeader,
+}
+
+impl From<ActionConfig> for ActionType {
+    fn from(action: ActionConfig) -> Self {
+        match action {
+            ActionConfig::Tagged(action) => action,
+            ActionConfig::Legacy(LegacyAction::Block) => {
+                ActionType::Block(BlockAction { message: None })
+            }
+            ActionConfig::Legacy(LegacyAction::InjectHeader) => ActionType::InjectHeader,
+        }
+    }
+}
+
+// This enum is used to decouple the state of Action application failure vs. blocking.
+pub enum ActionState {
+    Applied,
+    /// The request must be rejected, with the message to return to the client.
+    Blocked(String),
+}
+
+/// Apply a single action to the outgoing request. A `Blocked` result ends the
+/// chain, the caller is expected to reject the request.
+pub fn apply_action(
+    action: &ActionType,
+    headers: &mut HeaderMap<HeaderValue>,
+) -> Result<ActionState> {
+    match action {
+        ActionType::Block(block) => Ok(ActionState::Blocked(
+            block
+                .message
+                .clone()
+                .unwrap_or_else(|| DEFAULT_BLOCK_MESSAGE.to_string()),
+        )),
+        ActionType::InjectHeader => send_query_to_low_priority(headers),
+        ActionType::SetHeader(h) => set_header(headers, &h.name, &h.value),
+        ActionType::AppendHeader(h) => append_header(headers, &h.name, &h.value),
+        ActionType::RemoveHeader(h) => remove_header(headers, &h.name),
+        ActionType::AddClientTags(t) => add_client_tags(headers, &t.tags),
+        ActionType::SetSessionProperties(p) => {
+            set_session_properties(headers, &p.properties, p.override_existing)
+        }
+        ActionType::Named(n) => Err(anyhow!("action chain {} was not resolved", n.name)),
+    }
+}
+
+pub fn set_header(
+    headers: &mut HeaderMap<HeaderValue>,
+    name: &str,
+    value: &str,
+) -> Result<ActionState> {
+    headers.insert(
+        header::HeaderName::from_bytes(name.as_bytes())?,
+        HeaderValue::from_str(value)?,
+    );
+    Ok(ActionState::Applied)
+}
+
+/// Appends `value` to the existing header value as a comma separated list,
+/// or sets it if the header is not present.
+pub fn append_header(
+    headers: &mut HeaderMap<HeaderValue>,
+    name: &str,
+    value: &str,
+) -> Result<ActionState> {
+    let name = header::HeaderName::from_bytes(name.as_bytes())?;
+
+    let new_value = match headers.get(&name) {
+        Some(prev) if !prev.is_empty() => format!("{},{}", prev.to_str()?, value),
+        _ => value.to_string(),
+    };
+
+    headers.insert(name, HeaderValue::from_str(&new_value)?);
+    Ok(ActionState::Applied)
+}
+
+pub fn remove_header(headers: &mut HeaderMap<HeaderValue>, name: &str) -> Result<ActionState> {
+    headers.remove(header::HeaderName::from_bytes(name.as_bytes())?);
+    Ok(ActionState::Applied)
+}
+
+/// Adds the given tags to `X-Trino-Client-Tags`, skipping any the client already set.
+pub fn add_client_tags(
+    headers: &mut HeaderMap<HeaderValue>,
+    tags: &[String],
+) -> Result<ActionState> {
+    let prev_value = match headers.get(header::HeaderName::from_static(CLIENT_TAGS_HEADER)) {
+        Some(v) => v.to_str()?.to_string(),
+        None => String::new(),
+    };
+
+    // split the value on commas and add the new tags to the list
+    let mut values = prev_value
+        .split(',')
+        .map(str::trim)
+        .filter(|v| !v.is_empty())
+        .collect::<Vec<&str>>();
+    for tag in tags {
+        if !values.contains(&tag.as_str()) {
+            values.push(tag);
+        }
+    }
+
+    headers.insert(
+        header::HeaderName::from_static(CLIENT_TAGS_HEADER),
+        HeaderValue::from_str(&values.join(","))?,
+    );
+    Ok(ActionState::Applied)
+}
+
+pub fn send_query_to_low_priority(headers: &mut HeaderMap<HeaderValue>) -> Result<ActionState> {
+    add_client_tags(headers, &["lowprio".to_string()])
+}
+
+/// Merges `properties` into the `X-Trino-Session` header. Properties the client
+/// already set are left alone unless `override_existing` is set.
+pub fn set_session_properties(
+    headers: &mut HeaderMap<HeaderValue>,
+    properties: &BTreeMap<String, String>,
+    override_existing: bool,
+) -> Result<ActionState> {
+    let name = header::HeaderName::from_static(SESSION_HEADER);
+
+    // trino accepts the header multiple times, so collect every `key=value` pair
+    let mut session: Vec<(String, String)> = vec![];
+    for value in headers.get_all(&name) {
+        for pair in value.to_str()?.split(',') {
+            if let Some((k, v)) = pair.split_once('=') {
+                session.push((k.trim().to_string(), v.trim().to_string()));
+            }
+        }
+    }
+
+    for (key, value) in properties {
+        match session.iter_mut().find(|(k, _)| k == key) {
+            Some(existing) if override_existing => existing.1 = value.clone(),
+            Some(_) => {}
+            None => session.push((key.clone(), value.clone())),
+        }
+    }
+
+    let new_value = session
+        .iter()
+        .map(|(k, v)| format!("{}={}", k, v))
+        .collect::<Vec<String>>()
+        .join(",");
+
+    headers.insert(name, HeaderValue::from_str(&new_value)?);
+    Ok(ActionState::Applied)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn headers(pairs: &[(&str, &str)]) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        for (name, value) in pairs {
+            headers.append(
+                header::HeaderName::from_bytes(name.as_bytes()).unwrap(),
+                HeaderValue::from_str(value).unwrap(),
+            );
+        }
+        headers
+    }
+
+    fn values<'a>(headers: &'a HeaderMap, name: &str) -> Vec<&'a str> {
+        headers
+            .get_all(name)
+            .iter()
+            .map(|v| v.to_str().unwrap())
+            .collect()
+    }
+
+    fn properties(pairs: &[(&str, &str)]) -> BTreeMap<String, String> {
+        pairs
+            .iter()
+            .map(|(k, v)| (k.to_string(), v.to_string()))
+            .collect()
+    }
+
+    #[test]
+    fn set_header_replaces_every_value() {
+        let mut headers = headers(&[("x-trino-source", "a"), ("x-trino-source", "b")]);
+        set_header(&mut headers, "X-Trino-Source", "proxy").unwrap();
+        assert_eq!(values(&headers, "x-trino-source"), ["proxy"]);
+    }
+
+    #[test]
+    fn append_header() {
+        let mut session = headers(&[("x-trino-session", "a=1")]);
+        super::append_header(&mut session, "x-trino-session", "b=2").unwrap();
+        assert_eq!(values(&session, "x-trino-session"), ["a=1,b=2"]);
+
+        let mut empty = headers(&[("x-trino-session", "")]);
+        super::append_header(&mut empty, "x-trino-session", "b=2").unwrap();
+        assert_eq!(values(&empty, "x-trino-session"), ["b=2"]);
+
+        let mut unset = HeaderMap::new();
+        super::append_header(&mut unset, "x-trino-session", "b=2").unwrap();
+        assert_eq!(values(&unset, "x-trino-session"), ["b=2"]);
+    }
+
+    #[test]
+    fn remove_header_removes_every_value() {
+        let mut headers = headers(&[("x-trino-source", "a"), ("x-trino-source", "b")]);
+        remove_header(&mut headers, "x-trino-source").unwrap();
+        assert!(values(&headers, "x-trino-source").is_empty());
+    }
+
+    #[test]
+    fn invalid_header_names_and_values() {
+        let mut headers = HeaderMap::new();
+        assert!(set_header(&mut headers, "bad header", "v").is_err());
+        assert!(set_header(&mut headers, "x-ok", "bad\nvalue").is_err());
+        assert!(remove_header(&mut headers, "bad header").is_err());
+    }
+
+    #[test]
+    fn add_client_tags_keeps_existing_tags() {
+        let mut headers = headers(&[(CLIENT_TAGS_HEADER, "etl, lowprio,")]);
+        add_client_tags(&mut headers, &["lowprio".to_string(), "capped".to_string()]).unwrap();
+        assert_eq!(values(&headers, CLIENT_TAGS_HEADER), ["etl,lowprio,capped"]);
+
+        let mut headers = HeaderMap::new();
+        send_query_to_low_priority(&mut headers).unwrap();
+        assert_eq!(values(&headers, CLIENT_TAGS_HEADER), ["lowprio"]);
+    }
+
+    #[test]
+    fn session_properties_merge_with_the_clients() {
+        let mut headers = headers(&[(
+            SESSION_HEADER,
+            "query_max_run_time=1h, join_distribution_type=BROADCAST",
+        )]);
+        let properties = properties(&[
+            ("query_max_run_time", "10m"),
+            ("resource_overcommit", "false"),
+        ]);
+        set_session_properties(&mut headers, &properties, false).unwrap();
+        assert_eq!(
+            values(&headers, SESSION_HEADER),
+            ["query_max_run_time=1h,join_distribution_type=BROADCAST,resource_overcommit=false"]
+        );
+    }
+
+    #[test]
+    fn session_properties_override_the_clients() {
+        let mut headers = headers(&[(SESSION_HEADER, "query_max_run_time=1h")]);
+        let properties = properties(&[("query_max_run_time", "10m")]);
+        set_session_properties(&mut headers, &properties, true).unwrap();
+        assert_eq!(values(&headers, SESSION_HEADER), ["query_max_run_time=10m"]);
+    }
+
+    #[test]
+    fn session_properties_from_every_header_value() {
+        let mut headers = headers(&[(SESSION_HEADER, "a=1"), (SESSION_HEADER, "b=2,c=3")]);
+        let properties = properties(&[("b", "20"), ("d", "4")]);
+        set_session_properties(&mut headers, &properties, false).unwrap();
+        assert_eq!(values(&headers, SESSION_HEADER), ["a=1,b=2,c=3,d=4"]);
+    }
+
+    #[test]
+    fn session_properties_without_a_session() {
+        let mut headers = HeaderMap::new();
+        let properties = properties(&[("b", "2"), ("a", "1")]);
+        set_session_properties(&mut headers, &properties, false).unwrap();
+        assert_eq!(values(&headers, SESSION_HEADER), ["a=1,b=2"]);
+    }
+
+    #[test]
+    fn block_ends_the_chain_with_its_message() {
+        let mut headers = HeaderMap::new();
+        let block = ActionType::Block(BlockAction { message: None });
+        assert!(matches!(
+            apply_action(&block, &mut headers),
+            Ok(ActionState::Blocked(m)) if m == DEFAULT_BLOCK_MESSAGE
+        ));
+        let block = ActionType::Block(BlockAction {
+            message: Some("use the etl cluster".to_string()),
+        });
+        assert!(matches!(
+            apply_action(&block, &mut headers),
+            Ok(ActionState::Blocked(m)) if m == "use the etl cluster"
+        ));
+    }
+
+    #[test]
+    fn legacy_actions() {
+        let block: ActionConfig = serde_json::from_str(r#""Block""#).unwrap();
+        assert!(matches!(
+            ActionType::from(block),
+            ActionType::Block(BlockAction { message: None })
+        ));
+        let inject: ActionConfig = serde_json::from_str(r#""InjectHeader""#).unwrap();
+        assert!(matches!(ActionType::from(inject), ActionType::InjectHeader));
+        let tagged: ActionConfig =
+            serde_json::from_str(r#"{"type": "RemoveHeader", "name": "x-trino-source"}"#).unwrap();
+        assert!(matches!(
+            ActionType::from(tagged),
+            ActionType::RemoveHeader(_)
+        ));
+    }
+}
@@ -0,0 +1,200 @@
+use std::collections::{BTreeSet, HashSet};
+use std::fmt::Debug;
+use std::fs::OpenOptions;
+use std::io::{Read, Write};
+use std::net::IpAddr;
+use std::thread::JoinHandle;
+
+use anyhow::Result;
+
+use axum::http::header;
+
+use chrono::{DateTime, Utc};
+
+use flate2::read::GzDecoder;
+
+use http::Response;
+use hyper::Body;
+
+use serde::{Deserialize, Serialize};
+
+use sha2::{Digest, Sha256};
+
+use tokio::sync::mpsc;
+
+use tracing::warn;
+
+use crate::policy::{Client, Decision};
+use crate::routing::RouteDecision;
+use crate::statement::StatementKind;
+
+/// One line of the audit log, written for every `/v1/statement` request.
+#[derive(Debug, Serialize)]
+pub struct AuditRecord {
+    pub timestamp: DateTime<Utc>,
+    pub user: Option<String>,
+    pub source_ip: Option<IpAddr>,
+    /// The subject of the client certificate, when TLS client auth is enabled.
+    pub cert_subject: Option<String>,
+    /// Hex encoded SHA-256 of the query text.
+    pub query_hash: String,
+    /// Lowercased, sorted table references of the query.
+    pub tables: Vec<String>,
+    pub statement_kind: StatementKind,
+    /// The upstream the statement was routed to.
+    pub routing: Option<RouteDecision>,
+    #[serde(flatten)]
+    pub decision: Decision,
+    /// The query id assigned by the upstream cluster, if the query was forwarded.
+    pub query_id: Option<String>,
+}
+
+impl AuditRecord {
+    pub fn new(
+        client: &Client,
+        query: &str,
+        kind: StatementKind,
+        tables: &HashSet<String>,
+    ) -> Self {
+        AuditRecord {
+            timestamp: Utc::now(),
+            user: client.user.clone(),
+            source_ip: client.ip,
+            cert_subject: client.cert_subject.clone(),
+            query_hash: format!("{:x}", Sha256::digest(query.as_bytes())),
+            tables: tables
+                .iter()
+                .map(|t| t.to_lowercase())
+                .collect::<BTreeSet<String>>()
+                .into_iter()
+                .collect(),
+            statement_kind: kind,
+            routing: None,
+            decision: Decision::default(),
+            query_id: None,
+        }
+    }
+}
+
+/// A JSON-lines sink for [`AuditRecord`]s. Records are written and flushed
+/// by a dedicated thread, so a slow disk never holds up a request. Recording
+/// is a no-op when auditing is disabled.
+pub struct AuditLog {
+    records: Option<mpsc::UnboundedSender<String>>,
+    writer: Option<JoinHandle<()>>,
+}
+
+impl Debug for AuditLog {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("AuditLog")
+            .field("enabled", &self.records.is_some())
+            .finish()
+    }
+}
+
+impl AuditLog {
+    /// Open the audit log at `target`, either `stdout` or a file path that is
+    /// appended to. `None` disables auditing.
+    pub fn open(target: Option<&str>) -> Result<Self> {
+        let sink: Box<dyn Write + Send> = match target {
+            None => {
+                return Ok(AuditLog {
+                    records: None,
+                    writer: None,
+                })
+            }
+            Some("stdout") => Box::new(std::io::stdout()),
+            Some(path) => Box::new(OpenOptions::new().create(true).append(true).open(path)?),
+        };
+
+        let (records, receiver) = mpsc::unbounded_channel();
+        let writer = std::thread::Builder::new()
+            .name("audit-log".to_string())
+            .spawn(move || write_records(sink, receiver))?;
+        Ok(AuditLog {
+            records: Some(records),
+            writer: Some(writer),
+        })
+    }
+
+    pub fn record(&self, record: &AuditRecord) {
+        let Some(records) = &self.records else {
+            return;
+        };
+
+        let line = match serde_json::to_string(record) {
+            Ok(line) => line,
+            Err(e) => {
+                warn!("Could not serialize audit record: {}", e);
+                return;
+            }
+        };
+
+        if records.send(line).is_err() {
+            warn!("Could not write audit record: the audit log writer has stopped");
+        }
+    }
+}
+
+impl Drop for AuditLog {
+    /// Wait for the records still queued to be written.
+    fn drop(&mut self) {
+        self.records.take();
+        if let Some(writer) = self.writer.take() {
+            let _ = writer.join();
+        }
+    }
+}
+
+/// Write the records sent to the audit log until it is closed, flushing
+/// whenever no more records are waiting.
+fn write_records(mut sink: Box<dyn Write + Send>, mut records: mpsc::UnboundedReceiver<String>) {
+    while let Some(line) = records.blocking_recv() {
+        let mut res = writeln!(sink, "{}", line);
+        while let Ok(line) = records.try_recv() {
+            res = res.and_then(|_| writeln!(sink, "{}", line));
+        }
+        if let Err(e) = res.and_then(|_| sink.flush()) {
+            warn!("Could not write audit record: {}", e);
+        }
+    }
+}
+
+/// The part of the first response of a statement the proxy needs. Nothing
+/// else is parsed, so no other field can fail it.
+#[derive(Debug, Deserialize)]
+struct StatementResponse {
+    id: String,
+}
+
+/// Buffer the first response of a statement to pull the upstream query id out
+/// of it, returning an equivalent response to send on to the client.
+pub async fn capture_query_id(
+    res: Response<Body>,
+) -> Result<(Response<Body>, Option<String>), hyper::Error> {
+    let (parts, body) = res.into_parts();
+    let bytes = hyper::body::to_bytes(body).await?;
+
+    let decoded = if matches!(parts.headers.get(header::CONTENT_ENCODING), Some(v) if v == "gzip") {
+        let mut buf = vec![];
+        match GzDecoder::new(&bytes[..]).read_to_end(&mut buf) {
+            Ok(_) => buf,
+            Err(e) => {
+                warn!("Could not decompress statement response: {}", e);
+                vec![]
+            }
+        }
+    } else {
+        bytes.to_vec()
+    };
+
+    let query_id = match serde_json::from_slice::<StatementResponse>(&decoded) {
+        Ok(response) => Some(response.id),
+        Err(e) => {
+            warn!("Could not parse statement response: {}", e);
+            None
+        }
+    };
+
+    Ok((Response::from_parts(parts, Body::from(bytes)), query_id))
+}
@@ -1,4 +1,973 @@
-pub static STARPROXY_UPSTREAM_URL: once_cell::sync::Lazy<String> =
-    once_cell::sync::Lazy::new(|| {
-        std::env::var("STARPROXY_UPSTREAM_URL").unwrap_or_else(|_| "trino".to_string())
-    });
+use crate::actions::ActionType;
+use crate::networks::{read_cidr_file, NetworkRef, NetworkSet};
+use crate::routing::RoutingSettings;
+use crate::rules::{RuleConfig, RuleContainer, RuleEntry, UnknownEstimates};
+use crate::tls::{load_certs, ReloadingCertResolver, TlsSettings};
+
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::fmt;
+use std::net::{Ipv4Addr, SocketAddr};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use anyhow::{anyhow, Result};
+
+use cidr_utils::cidr::IpCidr;
+
+use http::header::HeaderName;
+use http::uri::Authority;
+use http::{HeaderValue, Uri};
+
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+
+use tracing_subscriber::EnvFilter;
+
+/// The version of the config file format understood by this build.
+pub const CONFIG_VERSION: u32 = 1;
+
+/// The upstream requests are sent to unless a route sends them elsewhere.
+pub const DEFAULT_UPSTREAM: &str = "default";
+
+const DEFAULT_CONFIG_PATH: &str = "/etc/starproxy/config.json";
+
+/// The config file, in JSON, YAML or TOML depending on its extension. Most
+/// settings can be overridden from the environment, see [`Settings::apply_env`].
+#[derive(Debug, Serialize, Deserialize, JsonSchema)]
+#[serde(deny_unknown_fields)]
+pub struct Settings {
+    /// The version of the config format, currently always 1.
+    #[serde(default = "default_version")]
+    pub version: u32,
+    #[serde(default)]
+    pub server: ServerSettings,
+    /// The clusters requests can be proxied to, by name. The `default`
+    /// upstream is required.
+    #[serde(default = "default_upstreams")]
+    pub upstreams: BTreeMap<String, UpstreamPoolSettings>,
+    /// Which upstream each new statement is sent to.
+    #[serde(default)]
+    pub routing: RoutingSettings,
+    #[serde(default)]
+    pub auth: AuthSettings,
+    #[serde(default)]
+    pub rules: Vec<RuleEntry>,
+    /// Named network sets that `CidrOrigin` rules can refer to.
+    #[serde(default)]
+    pub networks: HashMap<String, NetworkSet>,
+    /// Named action chains that rules can refer to with a `Named` action.
+    #[serde(default)]
+    pub actions: HashMap<String, Vec<ActionType>>,
+    #[serde(default)]
+    pub logging: LoggingSettings,
+}
+
+fn default_version() -> u32 {
+    CONFIG_VERSION
+}
+
+fn default_upstreams() -> BTreeMap<String, UpstreamPoolSettings> {
+    BTreeMap::from([(
+        DEFAULT_UPSTREAM.to_string(),
+        UpstreamPoolSettings::default(),
+    )])
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema)]
+#[serde(default, deny_unknown_fields)]
+pub struct ServerSettings {
+    pub listen_address: SocketAddr,
+    /// Require a PROXY protocol (v1 or v2) header on every incoming connection,
+    /// which are only accepted from the `trusted_proxies`.
+    pub proxy_protocol: bool,
+    /// The proxies whose forwarding headers are trusted when resolving the client IP.
+    #[schemars(with = "Vec<String>")]
+    pub trusted_proxies: Vec<IpCidr>,
+    /// Terminate TLS on the listener with this certificate and key.
+    pub tls: Option<TlsSettings>,
+    pub timeouts: TimeoutSettings,
+    /// The largest statement accepted on `POST /v1/statement`, in bytes.
+    pub max_statement_bytes: usize,
+    /// How long to keep serving queries already in flight after SIGTERM, in seconds.
+    pub shutdown_grace_secs: u64,
+    pub health_check: HealthCheckSettings,
+    /// How long a query stays bound to the coordinator that accepted it after
+    /// the last request for it, in seconds.
+    pub query_binding_ttl_secs: u64,
+    pub explain_cache: ExplainCacheSettings,
+}
+
+impl Default for ServerSettings {
+    fn default() -> Self {
+        ServerSettings {
+            listen_address: SocketAddr::from((Ipv4Addr::UNSPECIFIED, 3000)),
+            proxy_protocol: false,
+            trusted_proxies: vec![],
+            tls: None,
+            timeouts: TimeoutSettings::default(),
+            max_statement_bytes: 1024 * 1024,
+            shutdown_grace_secs: 30,
+            health_check: HealthCheckSettings::default(),
+            query_binding_ttl_secs: 600,
+            explain_cache: ExplainCacheSettings::default(),
+        }
+    }
+}
+
+impl ServerSettings {
+    pub fn query_binding_ttl(&self) -> Duration {
+        Duration::from_secs(self.query_binding_ttl_secs)
+    }
+}
+
+/// How long the plans of `EXPLAIN`s run by rules and routes are reused for
+/// identical statements.
+#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema)]
+#[serde(default, deny_unknown_fields)]
+pub struct ExplainCacheSettings {
+    pub ttl_secs: u64,
+    /// The number of plans kept, least recently used first out. 0 disables the cache.
+    pub max_entries: usize,
+}
+
+impl Default for ExplainCacheSettings {
+    fn default() -> Self {
+        ExplainCacheSettings {
+            ttl_secs: 60,
+            max_entries: 1000,
+        }
+    }
+}
+
+impl ExplainCacheSettings {
+    pub fn ttl(&self) -> Duration {
+        Duration::from_secs(self.ttl_secs)
+    }
+}
+
+/// How upstreams are probed for readiness, in seconds.
+#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema)]
+#[serde(default, deny_unknown_fields)]
+pub struct HealthCheckSettings {
+    pub interval_secs: u64,
+    pub timeout_secs: u64,
+}
+
+impl Default for HealthCheckSettings {
+    fn default() -> Self {
+        HealthCheckSettings {
+            interval_secs: 10,
+            timeout_secs: 2,
+        }
+    }
+}
+
+impl HealthCheckSettings {
+    pub fn interval(&self) -> Duration {
+        Duration::from_secs(self.interval_secs)
+    }
+
+    pub fn timeout(&self) -> Duration {
+        Duration::from_secs(self.timeout_secs)
+    }
+}
+
+/// Timeouts in seconds. Upstream timeouts bound the wait for the response
+/// headers, a response body is streamed for as long as it takes. `null`
+/// disables a timeout.
+#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema)]
+#[serde(default, deny_unknown_fields)]
+pub struct TimeoutSettings {
+    /// Budget for evaluating every rule against a statement, EXPLAINs included.
+    pub rule_evaluation_secs: u64,
+    /// Timeout for a single EXPLAIN or SHOW STATS run by a rule or route, from
+    /// submitting it until its result is in, however long it stays queued.
+    pub explain_secs: u64,
+    /// Timeout for establishing a connection to an upstream.
+    pub upstream_connect_secs: u64,
+    /// Submitting a statement with `POST /v1/statement`.
+    pub statement_secs: Option<u64>,
+    /// Fetching results with `GET /v1/statement/...`. Trino long-polls these,
+    /// and clients choose how long it may wait, so there is none by default.
+    pub result_fetch_secs: Option<u64>,
+    /// Every other request, e.g. the web UI.
+    pub other_secs: Option<u64>,
+}
+
+impl Default for TimeoutSettings {
+    fn default() -> Self {
+        TimeoutSettings {
+            rule_evaluation_secs: 30,
+            explain_secs: 20,
+            upstream_connect_secs: 5,
+            statement_secs: Some(60),
+            result_fetch_secs: None,
+            other_secs: Some(30),
+        }
+    }
+}
+
+impl TimeoutSettings {
+    pub fn rule_evaluation(&self) -> Duration {
+        Duration::from_secs(self.rule_evaluation_secs)
+    }
+
+    pub fn explain(&self) -> Duration {
+        Duration::from_secs(self.explain_secs)
+    }
+
+    pub fn upstream_connect(&self) -> Duration {
+        Duration::from_secs(self.upstream_connect_secs)
+    }
+
+    /// The timeout for proxying a request, by the method and path the client requested.
+    pub fn upstream_request(&self, method: &http::Method, path: &str) -> Option<Duration> {
+        let secs = match (method, path) {
+            (&http::Method::POST, "/v1/statement") => self.statement_secs,
+            (&http::Method::GET, path) if path.starts_with("/v1/statement/") => {
+                self.result_fetch_secs
+            }
+            _ => self.other_secs,
+        };
+        secs.map(Duration::from_secs)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, JsonSchema)]
+#[serde(rename_all = "lowercase")]
+pub enum UpstreamScheme {
+    Http,
+    Https,
+}
+
+impl fmt::Display for UpstreamScheme {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            UpstreamScheme::Http => write!(f, "http"),
+            UpstreamScheme::Https => write!(f, "https"),
+        }
+    }
+}
+
+/// How a pool picks the coordinator for a new query.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize, JsonSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum SelectionStrategy {
+    #[default]
+    RoundRobin,
+    /// The coordinator with the fewest running and queued queries, as reported
+    /// on `/v1/cluster` at its last health check, plus the queries this proxy
+    /// sent it since.
+    LeastOutstanding,
+    /// Weighted round robin, weighing each coordinator by the inverse of the
+    /// number of queued queries it reports on `/v1/cluster`.
+    WeightedQueued,
+}
+
+/// A pool of equivalent coordinators, e.g. active/standby coordinators across
+/// availability zones. New queries go to a healthy coordinator picked by the
+/// `strategy`, follow-up requests for a query go to the coordinator that
+/// accepted it.
+#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema)]
+#[serde(deny_unknown_fields)]
+pub struct UpstreamPoolSettings {
+    pub coordinators: Vec<UpstreamSettings>,
+    #[serde(default)]
+    pub strategy: SelectionStrategy,
+    /// How many other coordinators a new statement is retried on when the
+    /// coordinator picked for it can't be reached or isn't accepting queries.
+    #[serde(default = "default_statement_retries")]
+    pub statement_retries: usize,
+}
+
+fn default_statement_retries() -> usize {
+    1
+}
+
+impl Default for UpstreamPoolSettings {
+    fn default() -> Self {
+        UpstreamPoolSettings {
+            coordinators: vec![UpstreamSettings::default()],
+            strategy: SelectionStrategy::default(),
+            statement_retries: default_statement_retries(),
+        }
+    }
+}
+
+/// A Trino/Starburst coordinator requests are proxied to, used both for
+/// proxied requests and for the EXPLAINs run by rules.
+#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema)]
+#[serde(default, deny_unknown_fields)]
+pub struct UpstreamSettings {
+    pub scheme: UpstreamScheme,
+    pub host: String,
+    /// Defaults to the standard port of the scheme.
+    pub port: Option<u16>,
+    /// Prefix for every path, for coordinators served under a sub path.
+    pub base_path: String,
+}
+
+impl Default for UpstreamSettings {
+    fn default() -> Self {
+        UpstreamSettings {
+            scheme: UpstreamScheme::Https,
+            host: "trino".to_string(),
+            port: None,
+            base_path: String::new(),
+        }
+    }
+}
+
+impl UpstreamSettings {
+    /// The `host[:port]` of the upstream, as used in the `Host` header.
+    pub fn authority(&self) -> String {
+        match self.port {
+            Some(port) => format!("{}:{}", self.host, port),
+            None => self.host.clone(),
+        }
+    }
+
+    /// The upstream URI for a path (and query) as requested by the client.
+    pub fn uri(&self, path_and_query: &str) -> Result<Uri> {
+        Ok(format!(
+            "{}://{}{}{}",
+            self.scheme,
+            self.authority(),
+            self.base_path.trim_end_matches('/'),
+            path_and_query
+        )
+        .parse()?)
+    }
+
+    /// Set the upstream from a URL like `https://trino.company.dev:8443/trino`,
+    /// or just an authority like `trino.company.dev`, which keeps the scheme.
+    fn set_from_url(&mut self, url: &str) -> Result<()> {
+        let authority = if url.contains("://") {
+            let uri = url.parse::<Uri>()?;
+            self.scheme = match uri.scheme_str() {
+                Some("http") => UpstreamScheme::Http,
+                Some("https") => UpstreamScheme::Https,
+                _ => return Err(anyhow!("unsupported upstream scheme in {}", url)),
+            };
+            self.base_path = uri.path().trim_end_matches('/').to_string();
+            uri.authority()
+                .cloned()
+                .ok_or_else(|| anyhow!("no host in upstream URL {}", url))?
+        } else {
+            url.parse::<Authority>()?
+        };
+
+        self.host = authority.host().to_string();
+        self.port = authority.port_u16();
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize, JsonSchema)]
+#[serde(rename_all = "lowercase")]
+pub enum ClientAuth {
+    /// Reject clients that don't present a certificate.
+    #[default]
+    Required,
+    /// Only verify the certificates of clients that present one.
+    Optional,
+}
+
+/// How clients are authenticated by the proxy itself. Trino authentication is
+/// passed through to the upstream untouched.
+#[derive(Debug, Clone, Default, Serialize, Deserialize, JsonSchema)]
+#[serde(default, deny_unknown_fields)]
+pub struct AuthSettings {
+    /// CA bundle used to verify client certificates. Client certificates are
+    /// not requested if this is unset. Requires `server.tls`.
+    pub client_ca_path: Option<PathBuf>,
+    pub client_auth: ClientAuth,
+    /// A credential of the proxy's own for the `EXPLAIN`s run by rules and
+    /// routes, used instead of the credentials of the statement.
+    pub explain_credential: Option<ServiceCredentialSettings>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema)]
+#[serde(deny_unknown_fields)]
+pub struct ServiceCredentialSettings {
+    /// A file holding the `Authorization` header value to send, e.g.
+    /// `Basic <base64 user:password>` or `Bearer <token>`.
+    pub authorization_file: PathBuf,
+    /// Run the `EXPLAIN`s as the user of the statement by sending it as
+    /// `X-Trino-User`, which requires the service user to be allowed to
+    /// impersonate users in Trino. Otherwise they run as the service user.
+    #[serde(default = "default_impersonate")]
+    pub impersonate: bool,
+}
+
+fn default_impersonate() -> bool {
+    true
+}
+
+impl ServiceCredentialSettings {
+    /// Read the `Authorization` header value from the file.
+    pub fn load(&self) -> Result<HeaderValue> {
+        let authorization = std::fs::read_to_string(&self.authorization_file).map_err(|e| {
+            anyhow!(
+                "could not read {}: {}",
+                self.authorization_file.display(),
+                e
+            )
+        })?;
+        let mut value = HeaderValue::from_str(authorization.trim())?;
+        value.set_sensitive(true);
+        Ok(value)
+    }
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize, JsonSchema)]
+#[serde(default, deny_unknown_fields)]
+pub struct LoggingSettings {
+    /// A tracing filter such as `info,starproxy=debug`, used when `RUST_LOG`
+    /// is not set.
+    pub filter: Option<String>,
+    /// Where to write the audit log: `stdout`, a file path, or unset to disable it.
+    pub audit_log: Option<String>,
+}
+
+/// A problem with the config, with the path of the offending setting, e.g.
+/// `rules[2].actions[0].name`.
+#[derive(Debug, Clone)]
+pub struct ConfigError {
+    pub path: String,
+    pub message: String,
+}
+
+impl ConfigError {
+    fn new(path: impl Into<String>, message: impl fmt::Display) -> Self {
+        ConfigError {
+            path: path.into(),
+            message: message.to_string(),
+        }
+    }
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.path.is_empty() {
+            write!(f, "{}", self.message)
+        } else {
+            write!(f, "{}: {}", self.path, self.message)
+        }
+    }
+}
+
+/// The config file path, from the command line, `STARPROXY_CONFIG_PATH`, or the default.
+pub fn config_path(arg: Option<String>) -> PathBuf {
+    arg.or_else(|| std::env::var("STARPROXY_CONFIG_PATH").ok())
+        .unwrap_or_else(|| DEFAULT_CONFIG_PATH.to_string())
+        .into()
+}
+
+/// Deserialize the settings, keeping the path to the setting that failed.
+fn deserialize<'de, D>(deserializer: D) -> Result<Settings, ConfigError>
+where
+    D: serde::Deserializer<'de>,
+    D::Error: fmt::Display,
+{
+    serde_path_to_error::deserialize(deserializer).map_err(|e| {
+        let path = e.path().to_string();
+        let path = if path == "." { String::new() } else { path };
+        ConfigError::new(path, e.into_inner())
+    })
+}
+
+impl Settings {
+    /// Parse a config file, picking the format from its extension (JSON
+    /// unless it ends in `.yaml`, `.yml` or `.toml`).
+    pub fn parse(path: &Path, contents: &str) -> Result<Self, ConfigError> {
+        match path.extension().and_then(|e| e.to_str()) {
+            Some("yaml" | "yml") => deserialize(serde_yaml::Deserializer::from_str(contents)),
+            Some("toml") => deserialize(&mut toml::Deserializer::new(contents)),
+            _ => deserialize(&mut serde_json::Deserializer::from_str(contents)),
+        }
+    }
+
+    /// Read and parse the config file, then apply overrides from the
+    /// environment. The settings are not validated.
+    pub fn read(path: &Path) -> Result<Self, ConfigError> {
+        let contents = std::fs::read_to_string(path).map_err(|e| {
+            ConfigError::new("", format!("could not read {}: {}", path.display(), e))
+        })?;
+        let mut settings = Settings::parse(path, &contents)?;
+        settings.apply_env()?;
+        Ok(settings)
+    }
+
+    /// Read the config file and validate it, failing with every problem found.
+    pub fn load(path: &Path) -> Result<Self> {
+        let settings = Settings::read(path).map_err(|e| anyhow!("{}", e))?;
+        let errors = settings.validate();
+        if !errors.is_empty() {
+            let errors = errors.iter().map(|e| e.to_string()).collect::<Vec<_>>();
+            return Err(anyhow!("invalid config:\n{}", errors.join("\n")));
+        }
+        Ok(settings)
+    }
+
+    /// The JSON Schema of the config file, for editor support.
+    pub fn json_schema() -> serde_json::Value {
+        serde_json::to_value(schemars::schema_for!(Settings)).expect("schema is valid JSON")
+    }
+
+    /// Apply overrides from the environment:
+    ///
+    /// - `STARPROXY_LISTEN_ADDRESS`, e.g. `127.0.0.1:8080`
+    /// - `STARPROXY_PROXY_PROTOCOL=true`
+    /// - `STARPROXY_TRUSTED_PROXIES`, comma separated CIDRs
+    /// - `STARPROXY_TLS_CERT` and `STARPROXY_TLS_KEY`, `STARPROXY_TLS_CLIENT_CA`
+    ///   and `STARPROXY_TLS_CLIENT_AUTH`
+    /// - `STARPROXY_UPSTREAM_URL`, either a full URL or just `host[:port]`
+    /// - `STARPROXY_UPSTREAM_SCHEME`, `STARPROXY_UPSTREAM_HOST`,
+    ///   `STARPROXY_UPSTREAM_PORT` and `STARPROXY_UPSTREAM_BASE_PATH`, which
+    ///   take precedence over `STARPROXY_UPSTREAM_URL`
+    /// - `STARPROXY_AUDIT_LOG`
+    ///
+    /// The upstream overrides apply to the first coordinator of the `default` upstream.
+    pub fn apply_env(&mut self) -> Result<(), ConfigError> {
+        let var = |name: &str| std::env::var(name).ok();
+        let invalid = |name: &str, e: &dyn fmt::Display| ConfigError::new(name, e);
+
+        if let Some(addr) = var("STARPROXY_LISTEN_ADDRESS") {
+            self.server.listen_address = addr
+                .parse()
+                .map_err(|e| invalid("STARPROXY_LISTEN_ADDRESS", &e))?;
+        }
+        if let Some(proxy_protocol) = var("STARPROXY_PROXY_PROTOCOL") {
+            self.server.proxy_protocol = proxy_protocol == "true";
+        }
+        if let Some(proxies) = var("STARPROXY_TRUSTED_PROXIES") {
+            self.server.trusted_proxies = proxies
+                .split(',')
+                .map(str::trim)
+                .filter(|s| !s.is_empty())
+                .map(|s| {
+                    IpCidr::from_str(s)
+                        .map_err(|e| invalid("STARPROXY_TRUSTED_PROXIES", &format!("{:?}", e)))
+                })
+                .collect::<Result<_, _>>()?;
+        }
+        if let Some(cert_path) = var("STARPROXY_TLS_CERT") {
+            let key_path = var("STARPROXY_TLS_KEY").ok_or_else(|| {
+                invalid(
+                    "STARPROXY_TLS_KEY",
+                    &"must be set along with STARPROXY_TLS_CERT",
+                )
+            })?;
+            self.server.tls = Some(TlsSettings {
+                cert_path: cert_path.into(),
+                key_path: key_path.into(),
+            });
+        }
+        if let Some(ca_path) = var("STARPROXY_TLS_CLIENT_CA") {
+            self.auth.client_ca_path = Some(ca_path.into());
+        }
+        if let Some(client_auth) = var("STARPROXY_TLS_CLIENT_AUTH") {
+            self.auth.client_auth = serde_json::from_value(serde_json::Value::String(client_auth))
+                .map_err(|e| invalid("STARPROXY_TLS_CLIENT_AUTH", &e))?;
+        }
+
+        let pool = self
+            .upstreams
+            .entry(DEFAULT_UPSTREAM.to_string())
+            .or_default();
+        if pool.coordinators.is_empty() {
+            pool.coordinators.push(UpstreamSettings::default());
+        }
+        let upstream = &mut pool.coordinators[0];
+        if let Some(url) = var("STARPROXY_UPSTREAM_URL") {
+            upstream
+                .set_from_url(&url)
+                .map_err(|e| invalid("STARPROXY_UPSTREAM_URL", &e))?;
+        }
+        if let Some(scheme) = var("STARPROXY_UPSTREAM_SCHEME") {
+            upstream.scheme = serde_json::from_value(serde_json::Value::String(scheme))
+                .map_err(|e| invalid("STARPROXY_UPSTREAM_SCHEME", &e))?;
+        }
+        if let Some(host) = var("STARPROXY_UPSTREAM_HOST") {
+            upstream.host = host;
+        }
+        if let Some(port) = var("STARPROXY_UPSTREAM_PORT") {
+            upstream.port = Some(
+                port.parse()
+                    .map_err(|e| invalid("STARPROXY_UPSTREAM_PORT", &e))?,
+            );
+        }
+        if let Some(base_path) = var("STARPROXY_UPSTREAM_BASE_PATH") {
+            upstream.base_path = base_path;
+        }
+
+        if let Some(audit_log) = var("STARPROXY_AUDIT_LOG") {
+            self.logging.audit_log = Some(audit_log);
+        }
+        Ok(())
+    }
+
+    /// Check everything that can be checked without starting the proxy,
+    /// including that the files the config refers to load. Returns every
+    /// problem found rather than stopping at the first.
+    pub fn validate(&self) -> Vec<ConfigError> {
+        let mut errors = vec![];
+
+        if self.version != CONFIG_VERSION {
+            errors.push(ConfigError::new(
+                "version",
+                format!(
+                    "unsupported config version {}, expected {}",
+                    self.version, CONFIG_VERSION
+                ),
+            ));
+        }
+
+        if self.server.proxy_protocol && self.server.trusted_proxies.is_empty() {
+            errors.push(ConfigError::new(
+                "server.trusted_proxies",
+                "must list the proxies that send PROXY headers when proxy_protocol is enabled",
+            ));
+        }
+
+        let timeouts = &self.server.timeouts;
+        let zero_timeouts = [
+            ("rule_evaluation_secs", Some(timeouts.rule_evaluation_secs)),
+            ("explain_secs", Some(timeouts.explain_secs)),
+            (
+                "upstream_connect_secs",
+                Some(timeouts.upstream_connect_secs),
+            ),
+            ("statement_secs", timeouts.statement_secs),
+            ("result_fetch_secs", timeouts.result_fetch_secs),
+            ("other_secs", timeouts.other_secs),
+        ];
+        for (key, secs) in zero_timeouts {
+            if secs == Some(0) {
+                errors.push(ConfigError::new(
+                    format!("server.timeouts.{}", key),
+                    "must be greater than 0, use null to disable a timeout",
+                ));
+            }
+        }
+
+        let health_check = &self.server.health_check;
+        for (key, secs) in [
+            ("interval_secs", health_check.interval_secs),
+            ("timeout_secs", health_check.timeout_secs),
+        ] {
+            if secs == 0 {
+                errors.push(ConfigError::new(
+                    format!("server.health_check.{}", key),
+                    "must be greater than 0",
+                ));
+            }
+        }
+
+        if self.server.explain_cache.ttl_secs == 0 {
+            errors.push(ConfigError::new(
+                "server.explain_cache.ttl_secs",
+                "must be greater than 0, use max_entries: 0 to disable the cache",
+            ));
+        }
+
+        if self.server.max_statement_bytes == 0 {
+            errors.push(ConfigError::new(
+                "server.max_statement_bytes",
+                "must be greater than 0",
+            ));
+        }
+
+        if let Some(tls) = &self.server.tls {
+            if let Err(e) = ReloadingCertResolver::load(&tls.cert_path, &tls.key_path) {
+                errors.push(ConfigError::new("server.tls", e));
+            }
+        }
+
+        if !self.upstreams.contains_key(DEFAULT_UPSTREAM) {
+            errors.push(ConfigError::new(
+                "upstreams",
+                format!("the {} upstream is required", DEFAULT_UPSTREAM),
+            ));
+        }
+        for (name, pool) in self.upstreams.iter() {
+            if pool.coordinators.is_empty() {
+                errors.push(ConfigError::new(
+                    format!("upstreams.{}.coordinators", name),
+                    "at least one coordinator is required",
+                ));
+            }
+            for (i, upstream) in pool.coordinators.iter().enumerate() {
+                let path = format!("upstreams.{}.coordinators[{}]", name, i);
+                if upstream.host.is_empty() {
+                    errors.push(ConfigError::new(
+                        format!("{}.host", path),
+                        "must not be empty",
+                    ));
+                } else if let Err(e) = upstream.uri("/") {
+                    errors.push(ConfigError::new(path.clone(), e));
+                }
+                if !upstream.base_path.is_empty() && !upstream.base_path.starts_with('/') {
+                    errors.push(ConfigError::new(
+                        format!("{}.base_path", path),
+                        "must start with /",
+                    ));
+                }
+            }
+        }
+
+        if !self.upstreams.contains_key(&self.routing.default) {
+            errors.push(ConfigError::new(
+                "routing.default",
+                format!("undefined upstream {}", self.routing.default),
+            ));
+        }
+        let mut route_names = HashSet::new();
+        for (i, route) in self.routing.routes.iter().enumerate() {
+            let path = format!("routing.routes[{}]", i);
+            if route.name.is_empty() {
+                errors.push(ConfigError::new(
+                    format!("{}.name", path),
+                    "must not be empty",
+                ));
+            } else if !route_names.insert(&route.name) {
+                errors.push(ConfigError::new(
+                    format!("{}.name", path),
+                    format!("duplicate route name {}", route.name),
+                ));
+            }
+            if !self.upstreams.contains_key(&route.upstream) {
+                errors.push(ConfigError::new(
+                    format!("{}.upstream", path),
+                    format!("undefined upstream {}", route.upstream),
+                ));
+            }
+            if !route.has_conditions() {
+                errors.push(ConfigError::new(
+                    path.clone(),
+                    "needs at least one of tables, users, sources, client_tags or cost",
+                ));
+            }
+            if let Some(cost) = &route.cost {
+                for (key, max) in cost.bounds() {
+                    if matches!(max, Some(max) if max < 0.0) {
+                        errors.push(ConfigError::new(
+                            format!("{}.cost.{}", path, key),
+                            "must not be negative",
+                        ));
+                    }
+                }
+            }
+            for (j, pattern) in route.tables.iter().enumerate() {
+                if pattern.is_empty() {
+                    errors.push(ConfigError::new(
+                        format!("{}.tables[{}]", path, j),
+                        "must not be empty",
+                    ));
+                }
+            }
+        }
+
+        if let Some(ca_path) = &self.auth.client_ca_path {
+            if self.server.tls.is_none() {
+                errors.push(ConfigError::new(
+                    "auth.client_ca_path",
+                    "client certificates require server.tls",
+                ));
+            }
+            if let Err(e) = load_certs(ca_path) {
+                errors.push(ConfigError::new("auth.client_ca_path", e));
+            }
+        }
+
+        if let Some(credential) = &self.auth.explain_credential {
+            if let Err(e) = credential.load() {
+                errors.push(ConfigError::new(
+                    "auth.explain_credential.authorization_file",
+                    e,
+                ));
+            }
+        }
+
+        for (name, set) in self.networks.iter() {
+            if let Some(file) = &set.file {
+                if let Err(e) = read_cidr_file(file) {
+                    errors.push(ConfigError::new(format!("networks.{}.file", name), e));
+                }
+            }
+        }
+
+        for (name, actions) in self.actions.iter() {
+            for (i, action) in actions.iter().enumerate() {
+                let path = format!("actions.{}[{}]", name, i);
+                if let ActionType::Named(_) = action {
+                    errors.push(ConfigError::new(
+                        path,
+                        "named action chains cannot refer to other chains",
+                    ));
+                } else {
+                    self.validate_action(&path, action, &mut errors);
+                }
+            }
+        }
+
+        let mut rule_names = HashSet::new();
+        for (i, rule) in self.rules.iter().enumerate() {
+            self.validate_rule(&format!("rules[{}]", i), rule, &mut errors);
+            if !rule_names.insert(&rule.name) {
+                errors.push(ConfigError::new(
+                    format!("rules[{}].name", i),
+                    format!("duplicate rule name {}", rule.name),
+                ));
+            }
+        }
+
+        if let Some(filter) = &self.logging.filter {
+            if let Err(e) = EnvFilter::try_new(filter) {
+                errors.push(ConfigError::new("logging.filter", e));
+            }
+        }
+        if let Some(audit_log) = self.logging.audit_log.as_deref() {
+            let dir = Path::new(audit_log).parent();
+            if audit_log != "stdout"
+                && matches!(dir, Some(d) if !d.as_os_str().is_empty() && !d.is_dir())
+            {
+                errors.push(ConfigError::new(
+                    "logging.audit_log",
+                    "the directory does not exist",
+                ));
+            }
+        }
+
+        errors
+    }
+
+    fn validate_rule(&self, path: &str, rule: &RuleEntry, errors: &mut Vec<ConfigError>) {
+        if rule.name.is_empty() {
+            errors.push(ConfigError::new(
+                format!("{}.name", path),
+                "must not be empty",
+            ));
+        }
+        if rule.table_name.is_empty() {
+            errors.push(ConfigError::new(
+                format!("{}.table_name", path),
+                "must not be empty",
+            ));
+        }
+
+        match &rule.value {
+            RuleContainer::CidrOrigin(cidr) => {
+                let networks = [("allow", &cidr.allow), ("deny", &cidr.deny)];
+                for (key, refs) in networks {
+                    for (i, network) in refs.iter().enumerate() {
+                        if let NetworkRef::Named(name) = network {
+                            if !self.networks.contains_key(name) {
+                                errors.push(ConfigError::new(
+                                    format!("{}.value.{}[{}]", path, key, i),
+                                    format!("undefined network set {}", name),
+                                ));
+                            }
+                        }
+                    }
+                }
+            }
+            RuleContainer::ScanEstimates(cost) => {
+                let thresholds = [
+                    ("max_per_node", &cost.max_per_node),
+                    ("max_total", &cost.max_total),
+                ];
+                for (key, thresholds) in thresholds {
+                    for (dimension, max) in thresholds.bounds() {
+                        if matches!(max, Some(max) if max < 0.0) {
+                            errors.push(ConfigError::new(
+                                format!("{}.value.{}.{}", path, key, dimension),
+                                "must not be negative",
+                            ));
+                        }
+                    }
+                }
+                if matches!(cost.max_cpu_cost, Some(max) if max < 0.0) {
+                    errors.push(ConfigError::new(
+                        format!("{}.value.max_cpu_cost", path),
+                        "must not be negative",
+                    ));
+                }
+                if let UnknownEstimates::TableSize(limits) = &cost.unknown_estimates {
+                    let limits = [
+                        ("max_rows", limits.max_rows),
+                        ("max_bytes", limits.max_bytes),
+                    ];
+                    for (key, max) in limits {
+                        if matches!(max, Some(max) if max < 0.0) {
+                            errors.push(ConfigError::new(
+                                format!("{}.value.unknown_estimates.table_size.{}", path, key),
+                                "must not be negative",
+                            ));
+                        }
+                    }
+                }
+            }
+            RuleContainer::StatementKind(statement_kind) if statement_kind.deny.is_empty() => {
+                errors.push(ConfigError::new(
+                    format!("{}.value.deny", path),
+                    "must not be empty",
+                ));
+            }
+            _ => {}
+        }
+
+        for (i, action) in rule.actions.iter().enumerate() {
+            self.validate_action(&format!("{}.actions[{}]", path, i), action, errors);
+        }
+    }
+
+    fn validate_action(&self, path: &str, action: &ActionType, errors: &mut Vec<ConfigError>) {
+        match action {
+            ActionType::Named(named) if !self.actions.contains_key(&named.name) => {
+                errors.push(ConfigError::new(
+                    format!("{}.name", path),
+                    format!("undefined action chain {}", named.name),
+                ));
+            }
+            ActionType::SetHeader(h) | ActionType::AppendHeader(h) => {
+                if let Err(e) = HeaderName::from_bytes(h.name.as_bytes()) {
+                    errors.push(ConfigError::new(format!("{}.name", path), e));
+                }
+                if let Err(e) = HeaderValue::from_str(&h.value) {
+                    errors.push(ConfigError::new(format!("{}.value", path), e));
+                }
+            }
+            ActionType::RemoveHeader(h) => {
+                if let Err(e) = HeaderName::from_bytes(h.name.as_bytes()) {
+                    errors.push(ConfigError::new(format!("{}.name", path), e));
+                }
+            }
+            _ => {}
+        }
+    }
+
+    /// Move the rules and network sets out of the settings, with references
+    /// to named action chains replaced by the actions of the chain.
+    pub fn take_rules(&mut self) -> RuleConfig {
+        let mut rules = std::mem::take(&mut self.rules);
+        for rule in rules.iter_mut() {
+            rule.actions = rule
+                .actions
+                .drain(..)
+                .flat_map(|action| match action {
+                    ActionType::Named(named) => {
+                        self.actions.get(&named.name).cloned().unwrap_or_default()
+                    }
+                    action => vec![action],
+                })
+                .collect();
+        }
+
+        RuleConfig {
+            rules,
+            networks: std::mem::take(&mut self.networks),
+        }
+    }
+}
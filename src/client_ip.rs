@@ -0,0 +1,218 @@
+use std::net::{IpAddr, SocketAddr};
+
+use axum::http::header;
+
+use cidr_utils::cidr::IpCidr;
+
+use http::{HeaderMap, HeaderValue};
+
+/// Resolve the address of the client that originated a request.
+///
+/// The hops recorded in the `Forwarded` header (or `X-Forwarded-For` when there
+/// is no `Forwarded` header) are followed by the TCP peer, then walked right to
+/// left skipping any hop in `trusted_proxies`. The first untrusted hop is the
+/// client; if every hop is trusted the leftmost one is. Hops added before an
+/// untrusted proxy can be forged by the client, so they are never consulted.
+///
+/// Returns `None` if the client hop is not an IP address, e.g. an obfuscated
+/// `Forwarded` identifier like `for=unknown`.
+pub fn resolve_client_ip(
+    headers: &HeaderMap<HeaderValue>,
+    peer: Option<SocketAddr>,
+    trusted_proxies: &[IpCidr],
+) -> Option<IpAddr> {
+    let mut hops = forwarded_hops(headers);
+    if let Some(peer) = peer {
+        hops.push(Some(peer.ip()));
+    }
+
+    let is_trusted = |ip: &IpAddr| trusted_proxies.iter().any(|cidr| cidr.contains(*ip));
+
+    for hop in hops.iter().rev() {
+        match hop {
+            Some(ip) if is_trusted(ip) => continue,
+            _ => return *hop,
+        }
+    }
+
+    hops.first().copied().flatten()
+}
+
+/// The hops recorded by proxies, in the order they were appended.
+fn forwarded_hops(headers: &HeaderMap<HeaderValue>) -> Vec<Option<IpAddr>> {
+    let forwarded = header_values(headers, header::FORWARDED);
+    if !forwarded.is_empty() {
+        return forwarded
+            .iter()
+            .flat_map(|v| v.split(','))
+            .filter_map(|element| {
+                element.split(';').find_map(|pair| {
+                    let (key, value) = pair.split_once('=')?;
+                    key.trim()
+                        .eq_ignore_ascii_case("for")
+                        .then(|| parse_node(value))
+                })
+            })
+            .collect();
+    }
+
+    header_values(headers, header::HeaderName::from_static("x-forwarded-for"))
+        .iter()
+        .flat_map(|v| v.split(','))
+        .map(parse_node)
+        .collect()
+}
+
+/// Every value of a header, which may be sent more than once.
+fn header_values(headers: &HeaderMap<HeaderValue>, name: header::HeaderName) -> Vec<&str> {
+    headers
+        .get_all(name)
+        .iter()
+        .filter_map(|v| v.to_str().ok())
+        .collect()
+}
+
+/// Parse a single hop, which may be quoted and carry a port, e.g. `1.2.3.4`,
+/// `1.2.3.4:8080`, `2001:db8::1` or `"[2001:db8::1]:8080"`.
+fn parse_node(node: &str) -> Option<IpAddr> {
+    let node = node.trim().trim_matches('"');
+
+    if let Ok(ip) = node.parse::<IpAddr>() {
+        return Some(ip);
+    }
+
+    if let Some(rest) = node.strip_prefix('[') {
+        return rest.split(']').next()?.parse().ok();
+    }
+
+    node.parse::<SocketAddr>().ok().map(|addr| addr.ip())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn headers(pairs: &[(&str, &str)]) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        for (name, value) in pairs {
+            headers.append(
+                header::HeaderName::from_bytes(name.as_bytes()).unwrap(),
+                HeaderValue::from_str(value).unwrap(),
+            );
+        }
+        headers
+    }
+
+    fn cidrs(cidrs: &[&str]) -> Vec<IpCidr> {
+        cidrs.iter().map(|c| IpCidr::from_str(c).unwrap()).collect()
+    }
+
+    fn ip(ip: &str) -> Option<IpAddr> {
+        Some(ip.parse().unwrap())
+    }
+
+    fn peer(addr: &str) -> Option<SocketAddr> {
+        Some(addr.parse().unwrap())
+    }
+
+    #[test]
+    fn peer_without_forwarding_headers() {
+        let resolved = resolve_client_ip(&HeaderMap::new(), peer("10.0.0.5:4000"), &[]);
+        assert_eq!(resolved, ip("10.0.0.5"));
+    }
+
+    #[test]
+    fn forwarding_headers_of_untrusted_peer_are_ignored() {
+        let headers = headers(&[("x-forwarded-for", "1.2.3.4")]);
+        let resolved = resolve_client_ip(&headers, peer("10.0.0.5:4000"), &[]);
+        assert_eq!(resolved, ip("10.0.0.5"));
+    }
+
+    #[test]
+    fn x_forwarded_for_chain_through_trusted_proxies() {
+        let headers = headers(&[("x-forwarded-for", "6.6.6.6, 1.2.3.4, 10.0.0.2")]);
+        let trusted = cidrs(&["10.0.0.0/8"]);
+        let resolved = resolve_client_ip(&headers, peer("10.0.0.1:4000"), &trusted);
+        assert_eq!(resolved, ip("1.2.3.4"));
+    }
+
+    #[test]
+    fn x_forwarded_for_sent_more_than_once() {
+        let headers = headers(&[
+            ("x-forwarded-for", "6.6.6.6"),
+            ("x-forwarded-for", "1.2.3.4, 10.0.0.2"),
+        ]);
+        let trusted = cidrs(&["10.0.0.0/8"]);
+        let resolved = resolve_client_ip(&headers, peer("10.0.0.1:4000"), &trusted);
+        assert_eq!(resolved, ip("1.2.3.4"));
+    }
+
+    #[test]
+    fn every_hop_trusted_resolves_to_the_leftmost() {
+        let headers = headers(&[("x-forwarded-for", "10.0.0.3, 10.0.0.2")]);
+        let trusted = cidrs(&["10.0.0.0/8"]);
+        let resolved = resolve_client_ip(&headers, peer("10.0.0.1:4000"), &trusted);
+        assert_eq!(resolved, ip("10.0.0.3"));
+    }
+
+    #[test]
+    fn forwarded_takes_precedence_over_x_forwarded_for() {
+        let headers = headers(&[
+            ("forwarded", "for=1.2.3.4;proto=https, for=10.0.0.2"),
+            ("x-forwarded-for", "5.6.7.8"),
+        ]);
+        let trusted = cidrs(&["10.0.0.0/8"]);
+        let resolved = resolve_client_ip(&headers, peer("10.0.0.1:4000"), &trusted);
+        assert_eq!(resolved, ip("1.2.3.4"));
+    }
+
+    #[test]
+    fn forwarded_keys_are_case_insensitive() {
+        let headers = headers(&[("forwarded", "proto=http;For=1.2.3.4")]);
+        let trusted = cidrs(&["10.0.0.0/8"]);
+        let resolved = resolve_client_ip(&headers, peer("10.0.0.1:4000"), &trusted);
+        assert_eq!(resolved, ip("1.2.3.4"));
+    }
+
+    #[test]
+    fn forwarded_ipv6_with_brackets_and_port() {
+        let headers = headers(&[("forwarded", r#"for="[2001:db8::1]:8080""#)]);
+        let trusted = cidrs(&["10.0.0.0/8"]);
+        let resolved = resolve_client_ip(&headers, peer("10.0.0.1:4000"), &trusted);
+        assert_eq!(resolved, ip("2001:db8::1"));
+    }
+
+    #[test]
+    fn obfuscated_forwarded_identifier() {
+        let headers = headers(&[("forwarded", "for=unknown, for=10.0.0.2")]);
+        let trusted = cidrs(&["10.0.0.0/8"]);
+        let resolved = resolve_client_ip(&headers, peer("10.0.0.1:4000"), &trusted);
+        assert_eq!(resolved, None);
+    }
+
+    #[test]
+    fn ipv6_peer_and_trusted_proxies() {
+        let headers = headers(&[("x-forwarded-for", "2001:db8::1")]);
+        let trusted = cidrs(&["fd00::/8"]);
+        let resolved = resolve_client_ip(&headers, peer("[fd00::1]:4000"), &trusted);
+        assert_eq!(resolved, ip("2001:db8::1"));
+    }
+
+    #[test]
+    fn no_peer() {
+        let headers = headers(&[("x-forwarded-for", "1.2.3.4, 10.0.0.2")]);
+        let trusted = cidrs(&["10.0.0.0/8"]);
+        assert_eq!(resolve_client_ip(&headers, None, &trusted), ip("1.2.3.4"));
+    }
+
+    #[test]
+    fn parse_nodes() {
+        assert_eq!(parse_node("1.2.3.4"), ip("1.2.3.4"));
+        assert_eq!(parse_node(" 1.2.3.4:8080 "), ip("1.2.3.4"));
+        assert_eq!(parse_node("2001:db8::1"), ip("2001:db8::1"));
+        assert_eq!(parse_node("[2001:db8::1]"), ip("2001:db8::1"));
+        assert_eq!(parse_node(r#""[2001:db8::1]:8080""#), ip("2001:db8::1"));
+        assert_eq!(parse_node("_hidden"), None);
+        assert_eq!(parse_node(""), None);
+    }
+}
@@ -1,5 +1,36 @@
-use derive_visitor::Drive;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use anyhow::{anyhow, Result};
+use axum::http::header;
+use derive_visitor::{visitor_enter_fn, Drive};
+use http::{HeaderMap, HeaderValue};
 use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use sha2::{Digest, Sha256};
+use sqlparser::dialect::MySqlDialect;
+use sqlparser::parser::Parser as SQLParser;
+use tokio::sync::OnceCell;
+use tracing::debug;
+
+use crate::cfg::{AuthSettings, ServerSettings, UpstreamSettings};
+use crate::types::QueryResults;
+
+const USER_HEADER: &str = "x-trino-user";
+
+/// The headers of a statement's request that are forwarded with its `EXPLAIN`s:
+/// who it runs as and the session it is planned in. Everything else, hop-by-hop
+/// headers and the statement's own `Content-Length` and `Host` included, is dropped.
+const FORWARDED_HEADERS: &[&str] = &[
+    "authorization",
+    USER_HEADER,
+    "x-trino-catalog",
+    "x-trino-schema",
+    "x-trino-session",
+    "x-trino-extra-credential",
+    "x-trino-client-tags",
+];
 
 #[derive(Deserialize, Serialize, Debug, Drive)]
 pub struct ExplainNode {
@@ -35,10 +66,72 @@ pub enum FloatingPointHack {
     NaN(String),
 }
 
+impl FloatingPointHack {
+    /// The estimate, `None` when Trino has no statistics for it.
+    pub fn value(&self) -> Option<f64> {
+        match self {
+            FloatingPointHack::Float(v) if !v.is_nan() => Some(*v as f64),
+            _ => None,
+        }
+    }
+}
+
+/// The estimated cost of a whole plan. Trino's cost estimates are cumulative,
+/// so each cost is the largest one of any node. A cost is `None` when any
+/// node has no estimate for it.
+#[derive(Debug, Clone, Serialize)]
+pub struct PlanCost {
+    pub cpu_cost: Option<f64>,
+    pub memory_cost: Option<f64>,
+    pub network_cost: Option<f64>,
+    /// The size of the query's output, from the root node.
+    pub output_size_in_bytes: Option<f64>,
+}
+
+impl ExplainNode {
+    pub fn cost(&self) -> PlanCost {
+        let complete = |(max, unknown): (Option<f64>, bool)| {
+            if unknown {
+                None
+            } else {
+                Some(max.unwrap_or(0.0))
+            }
+        };
+        PlanCost {
+            cpu_cost: complete(self.max_known(|e| &e.cpu_cost)),
+            memory_cost: complete(self.max_known(|e| &e.memory_cost)),
+            network_cost: complete(self.max_known(|e| &e.network_cost)),
+            output_size_in_bytes: self
+                .estimates
+                .as_ref()
+                .and_then(|e| e.first())
+                .and_then(|e| e.output_size_in_bytes.value()),
+        }
+    }
+
+    /// The largest known estimate of any node, `None` if no node has one,
+    /// along with whether any node has no estimate.
+    pub fn max_known(&self, estimate: fn(&Estimate) -> &FloatingPointHack) -> (Option<f64>, bool) {
+        let mut max: Option<f64> = None;
+        let mut unknown = false;
+        self.drive(&mut visitor_enter_fn(|node: &ExplainNode| {
+            for e in node.estimates.iter().flatten() {
+                match estimate(e).value() {
+                    Some(v) => max = Some(max.map_or(v, |max| max.max(v))),
+                    None => unknown = true,
+                }
+            }
+        }));
+        (max, unknown)
+    }
+}
+
 #[derive(Deserialize, Serialize, Debug)]
 #[serde(rename_all = "camelCase")]
 #[non_exhaustive]
 pub struct Description {
+    /// The table handle of a scan, e.g. `hive:sales:orders`.
+    pub table: Option<String>,
     pub column_names: Option<String>,
     pub count: Option<String>,
     pub with_ties: Option<String>,
@@ -51,3 +144,443 @@ pub struct ColumnOutput {
     #[serde(rename = "type")]
     pub _type: String,
 }
+
+#[derive(Debug)]
+struct ServiceCredential {
+    authorization: HeaderValue,
+    impersonate: bool,
+}
+
+#[derive(Debug)]
+struct CachedPlan {
+    plan: Arc<ExplainNode>,
+    expires: Instant,
+    last_used: Instant,
+}
+
+/// Runs the `EXPLAIN`s of rules and routes over one long-lived client. Plans
+/// are cached by statement fingerprint, and concurrent EXPLAINs of the same
+/// statement share a single round trip.
+#[derive(Debug)]
+pub struct Explainer {
+    client: reqwest::Client,
+    credential: Option<ServiceCredential>,
+    cache: Mutex<HashMap<String, CachedPlan>>,
+    in_flight: Mutex<HashMap<String, Arc<OnceCell<Arc<ExplainNode>>>>>,
+    ttl: Duration,
+    max_entries: usize,
+    /// Bounds every `EXPLAIN` and `SHOW STATS` as a whole, including polling
+    /// while it is queued.
+    timeout: Duration,
+}
+
+impl Explainer {
+    pub fn new(settings: &ServerSettings, auth: &AuthSettings) -> Result<Self> {
+        let client = reqwest::ClientBuilder::new()
+            .connect_timeout(settings.timeouts.upstream_connect())
+            .timeout(settings.timeouts.explain())
+            .build()?;
+        let credential = match &auth.explain_credential {
+            Some(credential) => Some(ServiceCredential {
+                authorization: credential.load()?,
+                impersonate: credential.impersonate,
+            }),
+            None => None,
+        };
+        Ok(Explainer {
+            client,
+            credential,
+            cache: Mutex::new(HashMap::new()),
+            in_flight: Mutex::new(HashMap::new()),
+            ttl: settings.explain_cache.ttl(),
+            max_entries: settings.explain_cache.max_entries,
+            timeout: settings.timeouts.explain(),
+        })
+    }
+
+    /// The logical plan of a query, as `user` would get it from the upstream
+    /// with the session of `headers`.
+    pub async fn explain(
+        &self,
+        upstream: &UpstreamSettings,
+        headers: &HeaderMap,
+        user: Option<&str>,
+        query: &str,
+    ) -> Result<Arc<ExplainNode>> {
+        let headers = self.explain_headers(headers, user)?;
+        let key = cache_key(upstream, &headers, user, query);
+        if let Some(plan) = self.cached(&key) {
+            debug!("EXPLAIN cache hit for {}", key);
+            return Ok(plan);
+        }
+
+        // the first caller runs the EXPLAIN, everyone else waits for its plan
+        let cell = self
+            .in_flight
+            .lock()
+            .expect("in flight lock poisoned")
+            .entry(key.clone())
+            .or_default()
+            .clone();
+        let in_flight = InFlight {
+            explainer: self,
+            key: &key,
+            cell,
+        };
+        let res = in_flight
+            .cell
+            .get_or_try_init(|| async {
+                let explain = explain_logical_plan(&self.client, upstream, &headers, query);
+                match tokio::time::timeout(self.timeout, explain).await {
+                    Ok(plan) => plan.map(Arc::new),
+                    Err(_) => Err(anyhow!("EXPLAIN timed out after {:?}", self.timeout)),
+                }
+            })
+            .await
+            .cloned();
+
+        if let Ok(plan) = &res {
+            self.cache(key.clone(), plan.clone());
+        }
+        res
+    }
+
+    /// The size of a table as `user` would get it, see [`table_stats`].
+    pub async fn table_stats(
+        &self,
+        upstream: &UpstreamSettings,
+        headers: &HeaderMap,
+        user: Option<&str>,
+        table: &str,
+    ) -> Result<TableStats> {
+        let headers = self.explain_headers(headers, user)?;
+        let stats = table_stats(&self.client, upstream, &headers, table);
+        match tokio::time::timeout(self.timeout, stats).await {
+            Ok(stats) => stats,
+            Err(_) => Err(anyhow!("SHOW STATS timed out after {:?}", self.timeout)),
+        }
+    }
+
+    /// Whether there are credentials to run `EXPLAIN`s for a statement with.
+    pub fn has_credentials(&self, headers: &HeaderMap) -> bool {
+        self.credential.is_some() || headers.contains_key(header::AUTHORIZATION)
+    }
+
+    /// The headers to send with the `EXPLAIN`s of a statement: the allowed
+    /// headers of its request, with the service credential if there is one.
+    fn explain_headers(&self, headers: &HeaderMap, user: Option<&str>) -> Result<HeaderMap> {
+        let mut explain_headers = HeaderMap::new();
+        for name in FORWARDED_HEADERS {
+            for value in headers.get_all(*name) {
+                explain_headers.append(*name, value.clone());
+            }
+        }
+
+        match &self.credential {
+            Some(credential) => {
+                explain_headers.insert(header::AUTHORIZATION, credential.authorization.clone());
+                explain_headers.remove(USER_HEADER);
+                if let (true, Some(user)) = (credential.impersonate, user) {
+                    explain_headers.insert(USER_HEADER, HeaderValue::from_str(user)?);
+                }
+            }
+            // EXPLAINs run as the user of the statement
+            None if !explain_headers.contains_key(header::AUTHORIZATION) => {
+                return Err(anyhow!(
+                    "Authorization header not set, we can't run an EXPLAIN for this query"
+                ));
+            }
+            None => {}
+        }
+        Ok(explain_headers)
+    }
+
+    fn cached(&self, key: &str) -> Option<Arc<ExplainNode>> {
+        let mut cache = self.cache.lock().expect("explain cache lock poisoned");
+        let now = Instant::now();
+        match cache.get_mut(key) {
+            Some(cached) if cached.expires > now => {
+                cached.last_used = now;
+                Some(cached.plan.clone())
+            }
+            Some(_) => {
+                cache.remove(key);
+                None
+            }
+            None => None,
+        }
+    }
+
+    fn cache(&self, key: String, plan: Arc<ExplainNode>) {
+        if self.max_entries == 0 {
+            return;
+        }
+
+        let mut cache = self.cache.lock().expect("explain cache lock poisoned");
+        let now = Instant::now();
+        cache.insert(
+            key,
+            CachedPlan {
+                plan,
+                expires: now + self.ttl,
+                last_used: now,
+            },
+        );
+
+        // make room by dropping expired plans, then the least recently used
+        if cache.len() > self.max_entries {
+            cache.retain(|_, cached| cached.expires > now);
+        }
+        while cache.len() > self.max_entries {
+            let lru = cache
+                .iter()
+                .min_by_key(|(_, cached)| cached.last_used)
+                .map(|(key, _)| key.clone())
+                .expect("cache is not empty");
+            cache.remove(&lru);
+        }
+    }
+}
+
+/// A caller's share of an in-flight `EXPLAIN`. Dropping it, including when
+/// the caller gives up waiting, takes the `EXPLAIN` out of the in-flight map
+/// once it has a plan or nobody else is waiting for it.
+struct InFlight<'a> {
+    explainer: &'a Explainer,
+    key: &'a str,
+    cell: Arc<OnceCell<Arc<ExplainNode>>>,
+}
+
+impl Drop for InFlight<'_> {
+    fn drop(&mut self) {
+        let mut in_flight = self
+            .explainer
+            .in_flight
+            .lock()
+            .expect("in flight lock poisoned");
+        // the map holds one reference and this caller another
+        let done = self.cell.initialized() || Arc::strong_count(&self.cell) <= 2;
+        if done && matches!(in_flight.get(self.key), Some(c) if Arc::ptr_eq(c, &self.cell)) {
+            in_flight.remove(self.key);
+        }
+    }
+}
+
+/// Identifies the plan of a statement: a fingerprint of the query, normalized
+/// so formatting and keyword case don't matter, along with the upstream and
+/// the user, catalog, schema and session properties it is planned with.
+fn cache_key(
+    upstream: &UpstreamSettings,
+    headers: &HeaderMap,
+    user: Option<&str>,
+    query: &str,
+) -> String {
+    let header = |name: &str| {
+        headers
+            .get(name)
+            .and_then(|v| v.to_str().ok())
+            .unwrap_or_default()
+    };
+    // without a user the credentials identify who the plan is for
+    let user = user.unwrap_or_else(|| header("authorization"));
+
+    let mut hasher = Sha256::new();
+    for part in [
+        upstream.authority().as_str(),
+        user,
+        header("x-trino-catalog"),
+        header("x-trino-schema"),
+        header("x-trino-session"),
+        normalize(query).as_str(),
+    ] {
+        hasher.update(part.as_bytes());
+        hasher.update([0]);
+    }
+    format!("{:x}", hasher.finalize())
+}
+
+/// The query as printed from its AST, or with whitespace collapsed if it can't
+/// be parsed. Literals are kept, they change the estimates.
+fn normalize(query: &str) -> String {
+    match SQLParser::parse_sql(&MySqlDialect {}, query) {
+        Ok(statements) => statements
+            .iter()
+            .map(|s| s.to_string())
+            .collect::<Vec<String>>()
+            .join("; "),
+        Err(_) => query.split_whitespace().collect::<Vec<&str>>().join(" "),
+    }
+}
+
+/// Run a statement through the statement API of the upstream, following
+/// `nextUri` until it completes, and return every row of the result.
+async fn run_statement(
+    client: &reqwest::Client,
+    upstream: &UpstreamSettings,
+    headers: &HeaderMap,
+    statement: String,
+) -> Result<Vec<Value>> {
+    let mut results: QueryResults = client
+        .post(upstream.uri("/v1/statement")?.to_string())
+        .headers(headers.clone())
+        .body(statement)
+        .send()
+        .await?
+        .error_for_status()?
+        .json()
+        .await?;
+
+    let mut rows = vec![];
+    loop {
+        if let Some(error) = results.error {
+            return Err(anyhow!("{}", error));
+        }
+        rows.extend(results.data.unwrap_or_default());
+
+        let Some(next_uri) = results.next_uri else {
+            break;
+        };
+        results = client
+            .get(next_uri)
+            .headers(headers.clone())
+            .send()
+            .await?
+            .error_for_status()?
+            .json()
+            .await?;
+    }
+    Ok(rows)
+}
+
+/// Run `EXPLAIN (TYPE LOGICAL, FORMAT JSON)` for a query and return the root
+/// of the plan.
+pub async fn explain_logical_plan(
+    client: &reqwest::Client,
+    upstream: &UpstreamSettings,
+    headers: &HeaderMap,
+    query: &str,
+) -> Result<ExplainNode> {
+    let explain_query = format!("EXPLAIN (TYPE LOGICAL, FORMAT JSON) {}", query);
+    let rows = run_statement(client, upstream, headers, explain_query)
+        .await
+        .map_err(|e| anyhow!("EXPLAIN failed: {}", e))?;
+
+    // the plan is returned as a single row with a single JSON string column
+    let plan = rows
+        .first()
+        .and_then(|row| row.get(0))
+        .and_then(|plan| plan.as_str())
+        .ok_or_else(|| anyhow!("EXPLAIN returned no plan"))?;
+
+    Ok(serde_json::from_str(plan)?)
+}
+
+/// The size of a table according to its statistics, `None` where it has none.
+#[derive(Debug, Clone, Serialize)]
+pub struct TableStats {
+    pub row_count: Option<f64>,
+    pub data_size: Option<f64>,
+}
+
+/// Get the size of a table, e.g. `hive:sales:orders` as named in a plan,
+/// with `SHOW STATS`. Tables often have a row count even when the column
+/// statistics the planner needs for its estimates are missing.
+pub async fn table_stats(
+    client: &reqwest::Client,
+    upstream: &UpstreamSettings,
+    headers: &HeaderMap,
+    table: &str,
+) -> Result<TableStats> {
+    let name = table
+        .split(':')
+        .map(|part| format!("\"{}\"", part.replace('"', "\"\"")))
+        .collect::<Vec<String>>()
+        .join(".");
+    let rows = run_statement(
+        client,
+        upstream,
+        headers,
+        format!("SHOW STATS FOR {}", name),
+    )
+    .await
+    .map_err(|e| anyhow!("SHOW STATS failed: {}", e))?;
+
+    // the columns are column_name, data_size, distinct_values_count,
+    // nulls_fraction, row_count, low_value and high_value, with the row count
+    // on the summary row that has no column_name
+    let mut stats = TableStats {
+        row_count: None,
+        data_size: None,
+    };
+    for row in rows.iter() {
+        let value = |i: usize| row.get(i).and_then(|v| v.as_f64()).filter(|v| !v.is_nan());
+        if matches!(row.get(0), Some(Value::Null)) {
+            stats.row_count = value(4);
+        } else if let Some(size) = value(1) {
+            stats.data_size = Some(stats.data_size.unwrap_or(0.0) + size);
+        }
+    }
+    Ok(stats)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cfg::UpstreamScheme;
+
+    fn node(cpu_cost: Value, children: Vec<Value>) -> Value {
+        serde_json::json!({
+            "id": "0",
+            "name": "Node",
+            "descriptor": {},
+            "outputs": [],
+            "details": [],
+            "estimates": [{
+                "outputRowCount": 1.0,
+                "outputSizeInBytes": 1.0,
+                "cpuCost": cpu_cost,
+                "memoryCost": 0.0,
+                "networkCost": 0.0,
+            }],
+            "children": children,
+        })
+    }
+
+    #[test]
+    fn known_costs_survive_nodes_without_estimates() {
+        let plan = node(
+            serde_json::json!(1.0e6),
+            vec![node(serde_json::json!("NaN"), vec![])],
+        );
+        let plan: ExplainNode = serde_json::from_value(plan).unwrap();
+
+        assert_eq!(plan.max_known(|e| &e.cpu_cost), (Some(1.0e6), true));
+        assert_eq!(plan.max_known(|e| &e.memory_cost), (Some(0.0), false));
+        assert_eq!(plan.cost().cpu_cost, None);
+        assert_eq!(plan.cost().memory_cost, Some(0.0));
+    }
+
+    #[tokio::test]
+    async fn abandoned_explains_leave_the_in_flight_map() {
+        // accepts connections but never responds
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let upstream = UpstreamSettings {
+            scheme: UpstreamScheme::Http,
+            host: "127.0.0.1".to_string(),
+            port: Some(listener.local_addr().unwrap().port()),
+            ..UpstreamSettings::default()
+        };
+        let explainer =
+            Explainer::new(&ServerSettings::default(), &AuthSettings::default()).unwrap();
+        let mut headers = HeaderMap::new();
+        headers.insert(
+            header::AUTHORIZATION,
+            HeaderValue::from_static("Basic dGVzdA=="),
+        );
+
+        let explain = explainer.explain(&upstream, &headers, None, "SELECT 1");
+        let res = tokio::time::timeout(Duration::from_millis(50), explain).await;
+        assert!(res.is_err());
+        assert!(explainer.in_flight.lock().unwrap().is_empty());
+    }
+}
@@ -0,0 +1,153 @@
+use std::collections::BTreeMap;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+use axum::extract::State;
+use axum::http::StatusCode;
+use axum::response::IntoResponse;
+use axum::Json;
+
+use serde::Serialize;
+
+use tracing::info;
+
+use crate::upstream::{CoordinatorStatus, Upstreams};
+
+/// The state of the proxy as reported by the health endpoints.
+#[derive(Debug)]
+pub struct Health {
+    draining: AtomicBool,
+    upstreams: Arc<Upstreams>,
+}
+
+impl Health {
+    pub fn new(upstreams: Arc<Upstreams>) -> Self {
+        Health {
+            draining: AtomicBool::new(false),
+            upstreams,
+        }
+    }
+
+    /// Stop taking new statements, follow-up requests for queries already in
+    /// flight are still served.
+    pub fn start_draining(&self) {
+        self.draining.store(true, Ordering::SeqCst);
+    }
+
+    pub fn is_draining(&self) -> bool {
+        self.draining.load(Ordering::SeqCst)
+    }
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "snake_case")]
+enum ReadinessStatus {
+    Ready,
+    NotReady,
+    Draining,
+}
+
+#[derive(Debug, Serialize)]
+struct CoordinatorReport {
+    /// Unset until the coordinator has been checked.
+    #[serde(flatten)]
+    status: Option<CoordinatorStatus>,
+    outstanding_queries: usize,
+}
+
+#[derive(Debug, Serialize)]
+struct PoolReport {
+    available: bool,
+    coordinators: BTreeMap<String, CoordinatorReport>,
+}
+
+#[derive(Debug, Serialize)]
+struct Readiness {
+    status: ReadinessStatus,
+    upstreams: BTreeMap<String, PoolReport>,
+}
+
+/// `GET /healthz` and `GET /healthz/live`, a `200` for as long as the proxy
+/// is serving requests at all.
+pub async fn liveness_handler() -> impl IntoResponse {
+    Json(serde_json::json!({ "status": "alive" }))
+}
+
+/// `GET /healthz/ready`, a `200` when a coordinator of the default upstream
+/// is available, and a `503` otherwise, including
+/// once the proxy has started draining so load balancers stop sending it new
+/// clients. The body reports the status of every coordinator.
+pub async fn readiness_handler(State(health): State<Arc<Health>>) -> impl IntoResponse {
+    // the rules are loaded before the proxy starts serving, or it doesn't start
+    let status = if health.is_draining() {
+        ReadinessStatus::Draining
+    } else if health.upstreams.default_pool().is_available() {
+        ReadinessStatus::Ready
+    } else {
+        ReadinessStatus::NotReady
+    };
+
+    let code = match status {
+        ReadinessStatus::Ready => StatusCode::OK,
+        _ => StatusCode::SERVICE_UNAVAILABLE,
+    };
+    let upstreams = health
+        .upstreams
+        .pools()
+        .map(|pool| {
+            let coordinators = pool
+                .coordinators
+                .iter()
+                .map(|c| {
+                    let report = CoordinatorReport {
+                        status: c.status(),
+                        outstanding_queries: c.outstanding(),
+                    };
+                    (c.settings.authority(), report)
+                })
+                .collect();
+            let report = PoolReport {
+                available: pool.is_available(),
+                coordinators,
+            };
+            (pool.name.clone(), report)
+        })
+        .collect();
+    (code, Json(Readiness { status, upstreams }))
+}
+
+/// Resolves once the server should stop: on SIGTERM (or Ctrl-C) the proxy
+/// starts draining, then after `grace_period` the server stops accepting
+/// connections and finishes the requests it is serving.
+pub async fn shutdown_signal(health: Arc<Health>, grace_period: Duration) {
+    let ctrl_c = async {
+        tokio::signal::ctrl_c()
+            .await
+            .expect("Could not install Ctrl-C handler");
+    };
+
+    #[cfg(unix)]
+    let terminate = async {
+        tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate())
+            .expect("Could not install SIGTERM handler")
+            .recv()
+            .await;
+    };
+
+    #[cfg(not(unix))]
+    let terminate = std::future::pending::<()>();
+
+    tokio::select! {
+        _ = ctrl_c => {},
+        _ = terminate => {},
+    }
+
+    info!(
+        "Shutting down, serving queries in flight for {:?}",
+        grace_period
+    );
+    health.start_draining();
+    tokio::time::sleep(grace_period).await;
+    info!("Grace period over, closing connections");
+}
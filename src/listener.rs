@@ -0,0 +1,214 @@
+use std::io;
+use std::net::SocketAddr;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use std::time::Duration;
+
+use anyhow::{anyhow, Result};
+
+use axum::extract::connect_info::Connected;
+
+use cidr_utils::cidr::IpCidr;
+
+use hyper::server::accept::Accept;
+
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::mpsc;
+
+use tokio_rustls::server::TlsStream;
+use tokio_rustls::TlsAcceptor;
+
+use tracing::warn;
+
+use crate::proxy_protocol::read_proxy_header;
+use crate::tls::ClientCertificate;
+
+/// How long a client has to send its PROXY header and finish the TLS handshake
+/// before it is dropped.
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// The first and the longest pause after a failed `accept()`, e.g. when the
+/// process is out of file descriptors, doubling while the failures continue.
+const ACCEPT_BACKOFF_MIN: Duration = Duration::from_millis(10);
+const ACCEPT_BACKOFF_MAX: Duration = Duration::from_secs(1);
+
+/// What the listener knows about the client of a connection, available to
+/// handlers through `ConnectInfo<ConnectionInfo>`.
+#[derive(Debug, Clone)]
+pub struct ConnectionInfo {
+    /// The TCP peer, or the client announced in the PROXY header.
+    pub remote_addr: SocketAddr,
+    /// The verified client certificate, when client authentication is enabled.
+    pub client_cert: Option<ClientCertificate>,
+}
+
+enum Stream {
+    Plain(TcpStream),
+    Tls(Box<TlsStream<TcpStream>>),
+}
+
+/// An accepted connection, after any PROXY header and TLS handshake.
+pub struct Connection {
+    stream: Stream,
+    info: ConnectionInfo,
+}
+
+impl Connected<&Connection> for ConnectionInfo {
+    fn connect_info(target: &Connection) -> Self {
+        target.info.clone()
+    }
+}
+
+impl AsyncRead for Connection {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        match &mut self.stream {
+            Stream::Plain(s) => Pin::new(s).poll_read(cx, buf),
+            Stream::Tls(s) => Pin::new(s).poll_read(cx, buf),
+        }
+    }
+}
+
+impl AsyncWrite for Connection {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        match &mut self.stream {
+            Stream::Plain(s) => Pin::new(s).poll_write(cx, buf),
+            Stream::Tls(s) => Pin::new(s).poll_write(cx, buf),
+        }
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match &mut self.stream {
+            Stream::Plain(s) => Pin::new(s).poll_flush(cx),
+            Stream::Tls(s) => Pin::new(s).poll_flush(cx),
+        }
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match &mut self.stream {
+            Stream::Plain(s) => Pin::new(s).poll_shutdown(cx),
+            Stream::Tls(s) => Pin::new(s).poll_shutdown(cx),
+        }
+    }
+}
+
+/// The listener for the proxy. Depending on configuration each connection
+/// must start with a PROXY protocol (v1 or v2) header from a trusted proxy,
+/// and/or is wrapped in TLS. This work happens off the accept loop, so a slow client does not hold
+/// up other connections, and connections that fail it are dropped.
+pub struct Listener {
+    rx: mpsc::Receiver<Connection>,
+}
+
+impl Listener {
+    /// With `proxy_protocol` set, connections are only accepted from the
+    /// proxies listed in it, which must send a PROXY header.
+    pub async fn bind(
+        addr: SocketAddr,
+        proxy_protocol: Option<Vec<IpCidr>>,
+        tls: Option<TlsAcceptor>,
+    ) -> io::Result<Self> {
+        let proxy_protocol = proxy_protocol.map(Arc::<[IpCidr]>::from);
+        let listener = TcpListener::bind(addr).await?;
+        let (tx, rx) = mpsc::channel(128);
+
+        tokio::spawn(async move {
+            let mut backoff = ACCEPT_BACKOFF_MIN;
+            loop {
+                let (stream, peer) = match listener.accept().await {
+                    Ok(conn) => conn,
+                    Err(e) => {
+                        // retrying straight away would spin until the cause goes away
+                        warn!(
+                            "Error accepting connection, retrying in {:?}: {}",
+                            backoff, e
+                        );
+                        tokio::time::sleep(backoff).await;
+                        backoff = (backoff * 2).min(ACCEPT_BACKOFF_MAX);
+                        continue;
+                    }
+                };
+                backoff = ACCEPT_BACKOFF_MIN;
+
+                let tx = tx.clone();
+                let tls = tls.clone();
+                let proxy_protocol = proxy_protocol.clone();
+                tokio::spawn(async move {
+                    let handshake = handshake(stream, peer, proxy_protocol.as_deref(), tls);
+                    match tokio::time::timeout(HANDSHAKE_TIMEOUT, handshake).await {
+                        Ok(Ok(conn)) => {
+                            // the receiver is only gone once the server has shut down
+                            let _ = tx.send(conn).await;
+                        }
+                        Ok(Err(e)) => warn!("Dropping connection from {}: {}", peer, e),
+                        Err(_) => warn!("Timed out setting up connection from {}", peer),
+                    }
+                });
+            }
+        });
+
+        Ok(Listener { rx })
+    }
+}
+
+impl Accept for Listener {
+    type Conn = Connection;
+    type Error = io::Error;
+
+    fn poll_accept(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Result<Self::Conn, Self::Error>>> {
+        self.rx.poll_recv(cx).map(|conn| conn.map(Ok))
+    }
+}
+
+async fn handshake(
+    mut stream: TcpStream,
+    peer: SocketAddr,
+    proxy_protocol: Option<&[IpCidr]>,
+    tls: Option<TlsAcceptor>,
+) -> Result<Connection> {
+    // anyone else could announce any address they like
+    let remote_addr = match proxy_protocol {
+        Some(proxies) if proxies.iter().any(|cidr| cidr.contains(peer.ip())) => {
+            read_proxy_header(&mut stream, peer).await?
+        }
+        Some(_) => return Err(anyhow!("{} is not a trusted proxy", peer.ip())),
+        None => peer,
+    };
+
+    let Some(tls) = tls else {
+        return Ok(Connection {
+            stream: Stream::Plain(stream),
+            info: ConnectionInfo {
+                remote_addr,
+                client_cert: None,
+            },
+        });
+    };
+
+    let stream = tls.accept(stream).await?;
+    // the verifier has already checked the chain, this only extracts the identity
+    let client_cert = match stream.get_ref().1.peer_certificates() {
+        Some([cert, ..]) => Some(ClientCertificate::from_der(&cert.0)?),
+        _ => None,
+    };
+
+    Ok(Connection {
+        stream: Stream::Tls(Box::new(stream)),
+        info: ConnectionInfo {
+            remote_addr,
+            client_cert,
+        },
+    })
+}
@@ -1,71 +1,239 @@
 use axum::{
     body::{Bytes, HttpBody},
-    extract::State,
+    extract::{ConnectInfo, FromRef, State},
+    http::request::Parts,
     http::Request,
-    http::{header, HeaderValue, StatusCode},
-    response::IntoResponse,
-    routing::any,
-    Router,
+    http::{header, HeaderMap, HeaderValue, StatusCode},
+    response::{IntoResponse, Response},
+    routing::{any, get},
+    Json, Router,
 };
 
+use anyhow::{anyhow, Result};
 use axum_macros::debug_handler;
 use bytes::BytesMut;
-use http::uri::{Authority, Scheme};
+use cidr_utils::cidr::IpCidr;
+use serde::Serialize;
 
 use hyper_rustls::{HttpsConnector, HttpsConnectorBuilder};
 use tracing::{debug, info, warn};
 use tracing_subscriber::EnvFilter;
 
-use std::{
-    net::{Ipv4Addr, SocketAddr},
-    sync::Arc,
-    time::Duration,
-};
+use std::{path::PathBuf, sync::Arc, time::Duration};
+use tokio::time::Instant;
 use tower::ServiceBuilder;
 use tower_http::{
-    timeout::TimeoutLayer,
     trace::{DefaultMakeSpan, DefaultOnResponse, TraceLayer},
     LatencyUnit, ServiceBuilderExt,
 };
 
 use hyper::{client::HttpConnector, Body};
 
+pub mod actions;
+pub mod audit;
 pub mod cfg;
+pub mod client_ip;
 pub mod explain;
+pub mod health;
+pub mod listener;
+pub mod networks;
+pub mod policy;
+pub mod proxy_protocol;
+pub mod routing;
 pub mod rules;
+pub mod statement;
+pub mod tls;
 pub mod types;
+pub mod upstream;
 
-use crate::cfg::STARPROXY_UPSTREAM_URL;
+use crate::audit::{capture_query_id, AuditLog, AuditRecord};
+use crate::cfg::{config_path, Settings, UpstreamSettings};
+use crate::client_ip::resolve_client_ip;
+use crate::explain::{Explainer, PlanCost};
+use crate::health::{liveness_handler, readiness_handler, shutdown_signal, Health};
+use crate::listener::{ConnectionInfo, Listener};
+use crate::networks::reload_network_sets;
+use crate::policy::{evaluate, Client, Verdict};
 use crate::rules::*;
+use crate::statement::StatementKind;
+use crate::tls::tls_acceptor;
+use crate::upstream::{
+    check_upstreams, is_partial_cancel, query_id_from_path, Coordinator, Pool, Upstreams,
+};
+
+/// Setting this header to `true` on a statement returns the policy decision
+/// instead of forwarding the query upstream.
+const DRY_RUN_HEADER: &str = "x-starproxy-dry-run";
+
+/// How often the files backing network sets and the TLS certificate are
+/// checked for changes.
+const FILE_RELOAD_INTERVAL: Duration = Duration::from_secs(30);
+
+/// How often bindings of queries to coordinators are checked for expiry.
+const BINDING_EXPIRY_INTERVAL: Duration = Duration::from_secs(60);
+
+/// The body of errors starproxy returns itself, rather than ones from the
+/// upstream or the rules, e.g. `{"error": "STATEMENT_TOO_LARGE", "message": ...}`.
+#[derive(Debug, Serialize)]
+struct ErrorResponse {
+    error: &'static str,
+    message: String,
+}
+
+fn error_response(status: StatusCode, error: &'static str, message: String) -> Response {
+    (status, Json(ErrorResponse { error, message })).into_response()
+}
+
+/// Why the body of a statement could not be read.
+#[derive(Debug)]
+enum StatementBodyError {
+    TooLarge,
+    /// Usually the client going away mid-body.
+    Read(hyper::Error),
+    InvalidUtf8,
+}
+
+/// Read the body of a statement into memory, giving up as soon as it is known
+/// to be larger than `max_bytes`.
+async fn read_statement(body: &mut Body, max_bytes: usize) -> Result<String, StatementBodyError> {
+    // the lower bound is the Content-Length when the client sent one
+    let size_hint = body.size_hint().lower() as usize;
+    if size_hint > max_bytes {
+        return Err(StatementBodyError::TooLarge);
+    }
+
+    let mut buf = BytesMut::with_capacity(size_hint);
+    while let Some(chunk) = body.data().await {
+        let chunk = chunk.map_err(StatementBodyError::Read)?;
+        if buf.len() + chunk.len() > max_bytes {
+            return Err(StatementBodyError::TooLarge);
+        }
+        buf.extend_from_slice(&chunk);
+    }
+
+    String::from_utf8(buf.to_vec()).map_err(|_| StatementBodyError::InvalidUtf8)
+}
 
 #[derive(Clone, Debug)]
 struct OurState {
     client: hyper::Client<HttpsConnector<HttpConnector>>,
+    settings: Arc<Settings>,
     rules: Arc<RuleConfig>,
+    audit: Arc<AuditLog>,
+    trusted_proxies: Arc<Vec<IpCidr>>,
+    upstreams: Arc<Upstreams>,
+    health: Arc<Health>,
+    explainer: Arc<Explainer>,
+}
+
+impl FromRef<OurState> for Arc<Health> {
+    fn from_ref(state: &OurState) -> Self {
+        state.health.clone()
+    }
 }
 
 #[tokio::main]
 async fn main() {
-    // Setup tracing
-    tracing_subscriber::fmt()
-        .with_env_filter(EnvFilter::from_default_env())
-        .init();
+    let mut args = std::env::args().skip(1);
+    match args.next().as_deref() {
+        None => {}
+        Some("check-config") => std::process::exit(check_config(config_path(args.next()))),
+        Some("config-schema") => {
+            println!(
+                "{}",
+                serde_json::to_string_pretty(&Settings::json_schema()).unwrap()
+            );
+            return;
+        }
+        Some(command) => {
+            eprintln!(
+                "unknown command {}, expected check-config [PATH] or config-schema",
+                command
+            );
+            std::process::exit(2);
+        }
+    }
+
+    // Load the config file, it holds both the proxy settings and the rules
+    let mut settings = Settings::load(&config_path(None)).expect("Could not load config");
+    let config = settings.take_rules();
+
+    // Setup tracing, RUST_LOG takes precedence over the config file
+    let filter = match &settings.logging.filter {
+        Some(filter) if std::env::var_os(EnvFilter::DEFAULT_ENV).is_none() => {
+            EnvFilter::new(filter)
+        }
+        _ => EnvFilter::from_default_env(),
+    };
+    tracing_subscriber::fmt().with_env_filter(filter).init();
 
     // Run our service
-    let addr = SocketAddr::from((Ipv4Addr::UNSPECIFIED, 3000));
-    tracing::info!("Listening on {}", addr);
-    axum::Server::bind(&addr)
-        .serve(app().into_make_service())
+    let addr = settings.server.listen_address;
+
+    let tls = settings.server.tls.as_ref().map(|tls| {
+        let (acceptor, resolver) = tls_acceptor(tls, &settings.auth).expect("Could not set up TLS");
+
+        // pick up renewed certificates
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(FILE_RELOAD_INTERVAL);
+            loop {
+                interval.tick().await;
+                match resolver.reload() {
+                    Ok(true) => info!("Reloaded TLS certificate"),
+                    Ok(false) => {}
+                    Err(e) => warn!("Could not reload TLS certificate: {}", e),
+                }
+            }
+        });
+        acceptor
+    });
+
+    tracing::info!(
+        "Listening on {} (TLS: {}, PROXY protocol: {}), proxying to {:?}",
+        addr,
+        tls.is_some(),
+        settings.server.proxy_protocol,
+        settings.upstreams.keys().collect::<Vec<_>>()
+    );
+    let proxy_protocol = settings
+        .server
+        .proxy_protocol
+        .then(|| settings.server.trusted_proxies.clone());
+    let listener = Listener::bind(addr, proxy_protocol, tls)
+        .await
+        .expect("Could not bind listener");
+
+    let upstreams = Arc::new(Upstreams::new(
+        &settings.upstreams,
+        settings.server.query_binding_ttl(),
+    ));
+    let health = Arc::new(Health::new(upstreams.clone()));
+    let grace_period = Duration::from_secs(settings.server.shutdown_grace_secs);
+    axum::Server::builder(listener)
+        .serve(
+            app(settings, config, upstreams, health.clone())
+                .into_make_service_with_connect_info::<ConnectionInfo>(),
+        )
+        .with_graceful_shutdown(shutdown_signal(health, grace_period))
         .await
         .expect("server error");
 }
 
-fn app() -> Router {
+fn app(
+    settings: Settings,
+    mut config: RuleConfig,
+    upstreams: Arc<Upstreams>,
+    health: Arc<Health>,
+) -> Router {
+    let mut http = HttpConnector::new();
+    http.enforce_http(false);
+    http.set_connect_timeout(Some(settings.server.timeouts.upstream_connect()));
+
     let connector = HttpsConnectorBuilder::new()
         .with_native_roots()
-        .https_only()
+        .https_or_http()
         .enable_http1()
-        .build();
+        .wrap_connector(http);
 
     let client = hyper::Client::builder().build::<_, hyper::Body>(connector);
 
@@ -85,144 +253,503 @@ fn app() -> Router {
                 .on_response(DefaultOnResponse::new().latency_unit(LatencyUnit::Micros)),
         )
         .sensitive_response_headers(sensitive_headers)
-        // Set a timeout
-        .layer(TimeoutLayer::new(Duration::from_secs(30)))
         // Box the response body so it implements `Default` which is required by axum
         .map_response_body(axum::body::boxed);
 
-    // Open the config file and gather the rules into a RuleConfig struct.
-    let config_file = std::fs::read_to_string(
-        std::env::var("STARPROXY_CONFIG_PATH")
-            .unwrap_or_else(|_| "/etc/starproxy/config.json".to_string()),
-    )
-    .expect("Could not open config file");
-    let config: RuleConfig =
-        serde_json::from_str(&config_file).expect("Could not parse config file");
+    config.sort_by_priority();
+    config.load_networks().expect("Could not load network sets");
+    let config = Arc::new(config);
+
+    tokio::spawn(check_upstreams(
+        upstreams.clone(),
+        client.clone(),
+        settings.server.health_check.clone(),
+    ));
+
+    // forget queries the clients have abandoned
+    let expiring_upstreams = upstreams.clone();
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(BINDING_EXPIRY_INTERVAL);
+        loop {
+            interval.tick().await;
+            expiring_upstreams.expire_bindings();
+        }
+    });
+
+    // pick up changes to the files backing network sets
+    let reload_config = config.clone();
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(FILE_RELOAD_INTERVAL);
+        loop {
+            interval.tick().await;
+            reload_network_sets(reload_config.networks.iter());
+        }
+    });
+
+    let trusted_proxies = settings.server.trusted_proxies.clone();
+
+    let explainer =
+        Explainer::new(&settings.server, &settings.auth).expect("Failed to make http client");
+
+    let audit =
+        AuditLog::open(settings.logging.audit_log.as_deref()).expect("Could not open audit log");
 
     // Build route service
     Router::new()
+        .route("/healthz", get(liveness_handler))
+        .route("/healthz/live", get(liveness_handler))
+        .route("/healthz/ready", get(readiness_handler))
         .route("/*path", any(proxy_handler))
         .layer(middleware)
         .with_state(OurState {
             client,
-            rules: Arc::new(config),
+            settings: Arc::new(settings),
+            rules: config,
+            audit: Arc::new(audit),
+            trusted_proxies: Arc::new(trusted_proxies),
+            upstreams,
+            health,
+            explainer: Arc::new(explainer),
         })
 }
 
+/// Validate the config file, printing every error found. Returns the exit code.
+fn check_config(path: PathBuf) -> i32 {
+    let errors = match Settings::read(&path) {
+        Ok(settings) => settings.validate(),
+        Err(e) => vec![e],
+    };
+
+    if errors.is_empty() {
+        println!("{}: OK", path.display());
+        return 0;
+    }
+    for error in errors.iter() {
+        eprintln!("{}: {}", path.display(), error);
+    }
+    1
+}
+
 #[debug_handler]
 #[tracing::instrument(name = "proxy_handler", skip(state, req))]
-async fn proxy_handler(state: State<OurState>, mut req: Request<Body>) -> impl IntoResponse {
+async fn proxy_handler(
+    state: State<OurState>,
+    ConnectInfo(conn): ConnectInfo<ConnectionInfo>,
+    req: Request<Body>,
+) -> impl IntoResponse {
     debug!("incoming request headers: {:#?}", req.headers().keys());
+    let (mut parts, mut body) = req.into_parts();
 
-    let mut parts = req.uri().clone().into_parts();
-    parts.scheme = Some(Scheme::HTTPS);
-    parts.authority = Some(Authority::from_static(&STARPROXY_UPSTREAM_URL));
-    let new_url = http::Uri::from_parts(parts).expect("provided uri is not valid");
-    info!("new_url: {}", new_url);
-
-    *req.uri_mut() = new_url;
+    // match on the path as requested, before the upstream base path is prepended
+    let is_new_statement =
+        parts.method == http::Method::POST && parts.uri.path() == "/v1/statement";
 
-    info!("injecting HOST header: {:#?}", &STARPROXY_UPSTREAM_URL);
-    req.headers_mut().insert(
-        header::HOST,
-        HeaderValue::from_static(&STARPROXY_UPSTREAM_URL),
-    );
+    // while draining only follow-up requests for queries already in flight are served
+    if is_new_statement && state.health.is_draining() {
+        return error_response(
+            StatusCode::SERVICE_UNAVAILABLE,
+            "SERVER_SHUTTING_DOWN",
+            "starproxy is shutting down, retry the statement".to_string(),
+        );
+    }
 
-    if req.method() == http::Method::POST
-        && req.uri().path() == "/v1/statement"
-        && req
-            .headers()
-            .contains_key(header::HeaderName::from_static("authorization"))
-    {
-        // read the body of the request w/o consuming it
-        let buffer: Bytes = {
-            let body: &mut Body = req.body_mut();
-            let mut buf = BytesMut::with_capacity(body.size_hint().lower() as usize);
-            while let Some(chunk) = body.data().await {
-                buf.extend_from_slice(&chunk.unwrap());
+    // only new statements are buffered, so they can be evaluated and resent
+    // to another coordinator, every other request and response body is
+    // streamed through as is
+    let statement = if is_new_statement {
+        let max_bytes = state.settings.server.max_statement_bytes;
+        let body_str = match read_statement(&mut body, max_bytes).await {
+            Ok(body) => body,
+            Err(StatementBodyError::TooLarge) => {
+                return error_response(
+                    StatusCode::PAYLOAD_TOO_LARGE,
+                    "STATEMENT_TOO_LARGE",
+                    format!("Statements are limited to {} bytes", max_bytes),
+                );
+            }
+            Err(StatementBodyError::Read(e)) => {
+                debug!("Could not read statement body: {}", e);
+                return error_response(
+                    StatusCode::BAD_REQUEST,
+                    "STATEMENT_READ_FAILED",
+                    "Could not read the statement".to_string(),
+                );
+            }
+            Err(StatementBodyError::InvalidUtf8) => {
+                return error_response(
+                    StatusCode::BAD_REQUEST,
+                    "STATEMENT_INVALID_UTF8",
+                    "Statements must be valid UTF-8".to_string(),
+                );
             }
-            buf.freeze()
         };
+        Some(body_str)
+    } else {
+        None
+    };
 
-        let body_str = String::from_utf8(buffer.to_vec()).expect("invalid utf8");
+    let client = Client {
+        ip: resolve_client_ip(
+            &parts.headers,
+            Some(conn.remote_addr),
+            &state.trusted_proxies,
+        ),
+        user: conn
+            .client_cert
+            .as_ref()
+            .and_then(|c| c.common_name.clone())
+            .or_else(|| {
+                parts
+                    .headers
+                    .get(header::HeaderName::from_static("x-trino-user"))
+                    .and_then(|v| v.to_str().ok())
+                    .map(|v| v.to_string())
+            }),
+        cert_subject: conn.client_cert.as_ref().map(|c| c.subject.clone()),
+    };
 
-        // extract the table names from the query
+    // extract the table names from the query. Many valid Trino statements,
+    // e.g. CALL or SHOW STATS, can't be parsed, they reference no tables as
+    // far as routing is concerned, but the rules can't tell whether they
+    // reference a guarded table
+    let parsed_tables = statement.as_deref().and_then(|statement| {
+        extract_tables_from_query(statement)
+            .map_err(|e| debug!("Could not parse the statement: {}", e))
+            .ok()
+    });
+    let table_names = parsed_tables.clone().unwrap_or_default();
+    let statement_kind = statement
+        .as_deref()
+        .map(StatementKind::of)
+        .unwrap_or(StatementKind::Other);
 
-        let table_names =
-            extract_tables_from_query(&body_str).expect("could not extract tables from query");
+    // new statements are evaluated against the rules first, so routes see the
+    // request as mutated by their actions, e.g. a client tag they added
+    let mut audit_record = None;
+    if let Some(body_str) = statement.as_deref() {
+        let mut record = AuditRecord::new(&client, body_str, statement_kind, &table_names);
 
-        for rule in state.rules.rules.iter() {
-            let qd = QueryData {
-                query: body_str.clone(),
-                headers: req.headers().clone(),
-            };
+        // EXPLAINs run by rules go to the upstream of the default route, like
+        // those of routes, which is expected to have every catalog
+        let explain_upstream = state
+            .upstreams
+            .pool(&state.settings.routing.default)
+            .expect("routing.default refers to a defined upstream")
+            .primary();
+        record.decision = evaluate(
+            &state.rules,
+            &state.explainer,
+            &explain_upstream.settings,
+            &state.settings.server.timeouts,
+            body_str,
+            statement_kind,
+            parsed_tables.as_ref(),
+            &client,
+            &mut parts.headers,
+        )
+        .await;
+        info!(
+            "policy decision: {}",
+            serde_json::to_string(&record.decision).unwrap_or_default()
+        );
 
-            // if the table doesn't match the rule, we can skip the check
-            if !table_names.contains(&rule.table_name) {
-                continue;
+        let rejection = match &record.decision.verdict {
+            Verdict::Allow => None,
+            Verdict::Block { message, .. } => Some((StatusCode::FORBIDDEN, message.clone())),
+            Verdict::Error { message, .. } => {
+                Some((StatusCode::INTERNAL_SERVER_ERROR, message.clone()))
+            }
+        };
+        if let Some(rejection) = rejection {
+            state.audit.record(&record);
+            if is_dry_run(&parts.headers) {
+                return Json(record.decision).into_response();
             }
+            return rejection.into_response();
+        }
 
-            // marshalling logic betwen the config and evaluating the rule
-            let res = match &rule.value {
-                RuleContainer::CidrOrigin(cidr) => {
-                    inbound_cidr_check(&rule.table_name, cidr.inbound_cidr, &qd).await
-                }
-                RuleContainer::WhereClause(where_clause) => {
-                    check_for_predicate(&where_clause.filter, &rule.table_name, &qd).await
-                }
-                RuleContainer::SelectStarNoLimit(_ss) => {
-                    require_limit_if_select_star(&rule.table_name, &qd)
-                }
-                RuleContainer::ScanEstimates(_se) => {
-                    scan_estimates_check(&rule.table_name, _se.max_cpu_cost as f32, &qd).await
-                }
-            };
+        let plan_cost = || {
+            estimate_cost(
+                &state,
+                &parts.headers,
+                client.user.as_deref(),
+                body_str,
+                statement_kind,
+            )
+        };
+        match state
+            .settings
+            .routing
+            .route(&table_names, &client, &parts.headers, plan_cost)
+            .await
+        {
+            Ok(routing) => record.routing = Some(routing),
+            Err(e) => {
+                state.audit.record(&record);
+                return error_response(
+                    StatusCode::BAD_REQUEST,
+                    "QUERY_SPANS_UPSTREAMS",
+                    e.to_string(),
+                );
+            }
+        }
 
-            match res {
-                Ok(false) => {
-                    debug!("No violation of rule: {}", rule.name);
-                }
-                Ok(true) => {
-                    debug!("Violation of rule!: {}", rule.name);
-                    if let Some(func) = &rule.action {
-                        let action_result = match func {
-                            ActionType::Block => {
-                                return (StatusCode::FORBIDDEN, "Request blocked").into_response();
-                            }
-                            ActionType::InjectHeader => {
-                                send_query_to_low_priority(&mut req.headers_mut())
-                            }
-                        };
-
-                        match action_result {
-                            Ok(ActionState::Applied) => {
-                                debug!("Successfully applied action: {}", rule.name);
-                            }
-                            Err(e) => {
-                                warn!("Error applying action: {}", e);
-                                return (
-                                    StatusCode::INTERNAL_SERVER_ERROR,
-                                    "Error applying action",
-                                )
-                                    .into_response();
-                            }
-                        }
-                    }
-                }
-                Err(e) => {
-                    warn!("Error checking rule: {}", e);
-                    return (StatusCode::INTERNAL_SERVER_ERROR, "Error checking rule")
-                        .into_response();
-                }
+        // a dry run reports the decision to the client instead of forwarding the query
+        if is_dry_run(&parts.headers) {
+            state.audit.record(&record);
+            return Json(record.decision).into_response();
+        }
+        audit_record = Some(record);
+    }
+
+    // new queries go to a coordinator picked by the pool of their route,
+    // follow-up requests to the coordinator that accepted the query
+    let query_id = query_id_from_path(parts.uri.path()).map(|id| id.to_string());
+    let is_cancel = parts.method == http::Method::DELETE
+        && query_id.is_some()
+        && !is_partial_cancel(parts.uri.path());
+    let pool = match audit_record.as_ref().and_then(|r| r.routing.as_ref()) {
+        Some(routing) => state
+            .upstreams
+            .pool(&routing.upstream)
+            .expect("routes refer to defined upstreams"),
+        None => state.upstreams.default_pool(),
+    };
+    let coordinator = match &query_id {
+        _ if is_new_statement => pool.select(&[]),
+        // any other coordinator, and for routed queries any other cluster,
+        // doesn't know the query. Without a binding, e.g. after a restart or
+        // on another replica of the proxy, a lone coordinator is the only one
+        // that can
+        Some(id) => match state
+            .upstreams
+            .bound(id)
+            .or_else(|| state.upstreams.sole_coordinator())
+        {
+            Some(coordinator) => Some(coordinator),
+            None => {
+                return error_response(
+                    StatusCode::NOT_FOUND,
+                    "QUERY_NOT_FOUND",
+                    format!(
+                        "Query {} is not bound to a coordinator, it has expired or was not started through starproxy",
+                        id
+                    ),
+                );
             }
+        },
+        None => Some(pool.primary()),
+    };
+    let Some(coordinator) = coordinator else {
+        if let Some(record) = &audit_record {
+            state.audit.record(record);
+        }
+        return error_response(
+            StatusCode::SERVICE_UNAVAILABLE,
+            "NO_UPSTREAM_AVAILABLE",
+            format!("No coordinator of upstream {} is available", pool.name),
+        );
+    };
+
+    // the timeout only covers waiting for the upstream, not reading the body,
+    // routing or evaluating the rules, which have their own budgets
+    let deadline = state
+        .settings
+        .server
+        .timeouts
+        .upstream_request(&parts.method, parts.uri.path())
+        .map(|timeout| Instant::now() + timeout);
+    let res = match statement {
+        Some(statement) => {
+            send_statement(
+                &state,
+                pool,
+                coordinator,
+                &parts,
+                Bytes::from(statement),
+                deadline,
+            )
+            .await
+        }
+        None => {
+            let req = upstream_request(&parts, &coordinator.settings, body);
+            (coordinator, forward(&state, req, deadline).await)
         }
+    };
 
-        // replace the body with the original body
-        *req.body_mut() = Body::from(buffer);
+    // new queries are bound to the coordinator that accepted them, and audited
+    // once it has assigned them an id
+    let res = match res {
+        (coordinator, Ok(res)) if is_new_statement => {
+            let captured = capture_query_id(res).await;
+            let new_query_id = match &captured {
+                Ok((_, id)) => id.clone(),
+                Err(_) => None,
+            };
+            if let Some(id) = &new_query_id {
+                state.upstreams.bind(id, &coordinator);
+            }
+            if let Some(mut record) = audit_record {
+                record.query_id = new_query_id;
+                state.audit.record(&record);
+            }
+            captured.map_err(ForwardError::Upstream).map(|(res, _)| res)
+        }
+        (_, res) => {
+            if let Some(record) = &audit_record {
+                state.audit.record(record);
+            }
+            res
+        }
+    };
+
+    if is_cancel {
+        if let Some(id) = &query_id {
+            state.upstreams.unbind(id);
+        }
     }
 
-    match state.client.request(req).await {
+    match res {
         Ok(res) => res.into_response(),
-        Err(e) => (StatusCode::INTERNAL_SERVER_ERROR, format!("Error: {}", e)).into_response(),
+        Err(ForwardError::TimedOut) => {
+            (StatusCode::GATEWAY_TIMEOUT, "Upstream timed out").into_response()
+        }
+        Err(ForwardError::Upstream(e)) => {
+            (StatusCode::INTERNAL_SERVER_ERROR, format!("Error: {}", e)).into_response()
+        }
+    }
+}
+
+fn is_dry_run(headers: &HeaderMap) -> bool {
+    matches!(headers.get(DRY_RUN_HEADER), Some(v) if v == "true")
+}
+
+/// Estimate the cost of a query for routing with an `EXPLAIN` on the upstream
+/// of the default route, which is expected to have the catalogs of every
+/// other upstream it routes for. Statements without a plan have no cost.
+async fn estimate_cost(
+    state: &OurState,
+    headers: &HeaderMap,
+    user: Option<&str>,
+    query: &str,
+    kind: StatementKind,
+) -> Result<PlanCost> {
+    if !kind.is_explainable() {
+        return Err(anyhow!("{:?} statements are not explained", kind));
+    }
+    let pool = state
+        .upstreams
+        .pool(&state.settings.routing.default)
+        .expect("routing.default refers to a defined upstream");
+    let coordinator = pool
+        .select(&[])
+        .ok_or_else(|| anyhow!("no coordinator of upstream {} is available", pool.name))?;
+    let plan = state
+        .explainer
+        .explain(&coordinator.settings, headers, user, query)
+        .await?;
+    Ok(plan.cost())
+}
+
+/// Why a request could not be forwarded upstream.
+#[derive(Debug)]
+enum ForwardError {
+    /// No response before the route's timeout.
+    TimedOut,
+    Upstream(hyper::Error),
+}
+
+/// A copy of the incoming request pointed at a coordinator.
+fn upstream_request(parts: &Parts, upstream: &UpstreamSettings, body: Body) -> Request<Body> {
+    let path_and_query = parts
+        .uri
+        .path_and_query()
+        .map(|pq| pq.as_str())
+        .unwrap_or("/");
+    let new_url = upstream
+        .uri(path_and_query)
+        .expect("provided uri is not valid");
+    info!("new_url: {}", new_url);
+
+    let mut req = Request::new(body);
+    *req.method_mut() = parts.method.clone();
+    *req.uri_mut() = new_url;
+    *req.version_mut() = parts.version;
+    *req.headers_mut() = parts.headers.clone();
+
+    let host = upstream.authority();
+    info!("injecting HOST header: {:#?}", host);
+    req.headers_mut().insert(
+        header::HOST,
+        HeaderValue::from_str(&host).expect("upstream host is not a valid header value"),
+    );
+    req
+}
+
+async fn forward(
+    state: &OurState,
+    req: Request<Body>,
+    deadline: Option<Instant>,
+) -> Result<Response<Body>, ForwardError> {
+    let request = state.client.request(req);
+    let res = match deadline {
+        Some(deadline) => tokio::time::timeout_at(deadline, request)
+            .await
+            .map_err(|_| ForwardError::TimedOut)?,
+        None => request.await,
+    };
+    res.map_err(ForwardError::Upstream)
+}
+
+/// Send a new statement, failing over to another coordinator of the pool, up
+/// to the pool's `statement_retries`, when a coordinator can't be connected
+/// to or answers `503` because it is starting or shutting down. Neither can
+/// have started the query, so resending it is safe; timeouts and any other
+/// failure are not retried. Every attempt shares the route's deadline.
+/// Returns the coordinator of the last attempt.
+async fn send_statement(
+    state: &OurState,
+    pool: &Pool,
+    mut coordinator: Arc<Coordinator>,
+    parts: &Parts,
+    statement: Bytes,
+    deadline: Option<Instant>,
+) -> (Arc<Coordinator>, Result<Response<Body>, ForwardError>) {
+    let mut tried = Vec::new();
+    loop {
+        let req = upstream_request(parts, &coordinator.settings, Body::from(statement.clone()));
+        let res = forward(state, req, deadline).await;
+        let reason = match &res {
+            Err(ForwardError::Upstream(e)) if e.is_connect() => {
+                format!("could not connect: {}", e)
+            }
+            Ok(res) if res.status() == StatusCode::SERVICE_UNAVAILABLE => {
+                "the coordinator is not accepting queries".to_string()
+            }
+            _ => return (coordinator, res),
+        };
+
+        let name = coordinator.settings.authority();
+        warn!(
+            "Upstream {} ({}) failed a statement: {}",
+            pool.name, name, reason
+        );
+        coordinator.mark_unavailable(reason);
+        tried.push(coordinator.clone());
+
+        let next = if tried.len() > pool.statement_retries {
+            None
+        } else {
+            pool.select(&tried)
+        };
+        let Some(next) = next else {
+            return (coordinator, res);
+        };
+        info!(
+            "Retrying the statement on {} ({})",
+            pool.name,
+            next.settings.authority()
+        );
+        coordinator = next;
     }
 }
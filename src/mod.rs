@@ -1,4 +1,16 @@
+pub mod actions;
+pub mod audit;
 pub mod cfg;
+pub mod client_ip;
 pub mod explain;
+pub mod health;
+pub mod listener;
+pub mod networks;
+pub mod policy;
+pub mod proxy_protocol;
+pub mod routing;
 pub mod rules;
+pub mod statement;
+pub mod tls;
 pub mod types;
+pub mod upstream;
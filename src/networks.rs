@@ -0,0 +1,95 @@
+use std::net::IpAddr;
+use std::path::{Path, PathBuf};
+use std::sync::{Mutex, RwLock};
+use std::time::SystemTime;
+
+use anyhow::{anyhow, Result};
+
+use cidr_utils::cidr::IpCidr;
+
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+
+use tracing::{info, warn};
+
+/// A named set of networks, defined once in the config and referenced by name
+/// from any number of rules. CIDRs can be listed inline, read from a file of
+/// one CIDR per line (`#` starts a comment), or both.
+#[derive(Debug, Default, Serialize, Deserialize, JsonSchema)]
+#[serde(deny_unknown_fields)]
+pub struct NetworkSet {
+    #[serde(default)]
+    #[schemars(with = "Vec<String>")]
+    pub cidrs: Vec<IpCidr>,
+    pub file: Option<PathBuf>,
+    #[serde(skip)]
+    file_cidrs: RwLock<Vec<IpCidr>>,
+    #[serde(skip)]
+    file_modified: Mutex<Option<SystemTime>>,
+}
+
+/// A network in a rule, either a CIDR literal or the name of a [`NetworkSet`].
+#[derive(Debug, Serialize, Deserialize, JsonSchema)]
+#[serde(untagged)]
+pub enum NetworkRef {
+    Cidr(#[schemars(with = "String")] IpCidr),
+    Named(String),
+}
+
+impl NetworkSet {
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        self.cidrs.iter().any(|c| c.contains(ip))
+            || self
+                .file_cidrs
+                .read()
+                .expect("network set lock poisoned")
+                .iter()
+                .any(|c| c.contains(ip))
+    }
+
+    /// Re-read the backing file if it changed since it was last loaded. Returns
+    /// whether the set was updated.
+    pub fn reload(&self) -> Result<bool> {
+        let Some(path) = &self.file else {
+            return Ok(false);
+        };
+
+        let modified = std::fs::metadata(path)?.modified()?;
+        let mut last_modified = self
+            .file_modified
+            .lock()
+            .expect("network set lock poisoned");
+        if *last_modified == Some(modified) {
+            return Ok(false);
+        }
+
+        let cidrs = read_cidr_file(path)?;
+        *self.file_cidrs.write().expect("network set lock poisoned") = cidrs;
+        *last_modified = Some(modified);
+        Ok(true)
+    }
+}
+
+pub(crate) fn read_cidr_file(path: &Path) -> Result<Vec<IpCidr>> {
+    std::fs::read_to_string(path)?
+        .lines()
+        .map(|line| line.split('#').next().unwrap_or_default().trim())
+        .filter(|line| !line.is_empty())
+        .map(|line| {
+            IpCidr::from_str(line)
+                .map_err(|e| anyhow!("invalid CIDR {:?} in {}: {:?}", line, path.display(), e))
+        })
+        .collect()
+}
+
+/// Reload every network set backed by a file, keeping the previous contents
+/// of any file that fails to load.
+pub fn reload_network_sets<'a>(sets: impl Iterator<Item = (&'a String, &'a NetworkSet)>) {
+    for (name, set) in sets {
+        match set.reload() {
+            Ok(true) => info!("Reloaded network set {}", name),
+            Ok(false) => {}
+            Err(e) => warn!("Could not reload network set {}: {}", name, e),
+        }
+    }
+}
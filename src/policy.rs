@@ -0,0 +1,431 @@
+use std::collections::HashSet;
+use std::net::IpAddr;
+
+use anyhow::anyhow;
+
+use http::HeaderMap;
+use http::HeaderValue;
+
+use serde::Serialize;
+
+use tracing::{debug, warn};
+
+use crate::actions::{apply_action, ActionState, ActionType};
+use crate::cfg::{TimeoutSettings, UpstreamSettings};
+use crate::explain::Explainer;
+use crate::rules::*;
+use crate::statement::StatementKind;
+
+/// Who sent a request, as far as starproxy can tell.
+#[derive(Debug, Clone, Default)]
+pub struct Client {
+    pub ip: Option<IpAddr>,
+    /// The user rules are scoped to: the common name of the verified client
+    /// certificate if there is one, otherwise `X-Trino-User`.
+    pub user: Option<String>,
+    /// The subject of the verified client certificate.
+    pub cert_subject: Option<String>,
+}
+
+/// A rule that was violated by the query.
+#[derive(Debug, Serialize)]
+pub struct RuleMatch {
+    pub rule: String,
+    pub priority: i32,
+}
+
+/// The outcome of evaluating a single rule.
+#[derive(Debug, Serialize)]
+#[serde(tag = "type")]
+pub enum RuleOutcome {
+    /// The query does not reference the rule's table, the rule is scoped to
+    /// other users or kinds of statements, or it runs an `EXPLAIN` and the
+    /// statement has no plan to explain.
+    NotApplicable,
+    Passed,
+    Violated,
+    Error {
+        message: String,
+    },
+}
+
+#[derive(Debug, Serialize)]
+pub struct RuleEvaluation {
+    pub rule: String,
+    pub outcome: RuleOutcome,
+    /// How a `ScanEstimates` rule dealt with estimates that were unknown.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub unknown_estimates: Option<UnknownEstimatesReport>,
+}
+
+/// An action that was applied to the request, in application order.
+#[derive(Debug, Serialize)]
+pub struct AppliedAction {
+    pub rule: String,
+    pub action: ActionType,
+}
+
+/// The final verdict for a request after all rules have been evaluated.
+#[derive(Debug, Serialize)]
+#[serde(tag = "type")]
+pub enum Verdict {
+    Allow,
+    Block { rule: String, message: String },
+    Error { rule: String, message: String },
+}
+
+/// The result of evaluating a query against the rule config: the outcome of
+/// every rule evaluated, the rules that matched, every action applied and the
+/// resulting verdict.
+#[derive(Debug, Serialize)]
+pub struct Decision {
+    pub evaluated: Vec<RuleEvaluation>,
+    pub matched: Vec<RuleMatch>,
+    pub actions: Vec<AppliedAction>,
+    pub verdict: Verdict,
+}
+
+impl Default for Decision {
+    fn default() -> Self {
+        Decision {
+            evaluated: vec![],
+            matched: vec![],
+            actions: vec![],
+            verdict: Verdict::Allow,
+        }
+    }
+}
+
+impl Decision {
+    fn record(&mut self, rule: &RuleEntry, outcome: RuleOutcome) {
+        self.record_with_estimates(rule, outcome, None);
+    }
+
+    fn record_with_estimates(
+        &mut self,
+        rule: &RuleEntry,
+        outcome: RuleOutcome,
+        unknown_estimates: Option<UnknownEstimatesReport>,
+    ) {
+        self.evaluated.push(RuleEvaluation {
+            rule: rule.name.clone(),
+            outcome,
+            unknown_estimates,
+        });
+    }
+}
+
+/// Evaluate the rules against a query, applying the actions of violated rules
+/// to `headers`.
+///
+/// Rules are evaluated in priority order (see [`RuleConfig::sort_by_priority`]).
+/// A `Block` action or an error stops evaluation immediately. A violated rule
+/// with `stop_on_match` set stops evaluation after its actions are applied.
+/// Otherwise later rules see the request as mutated by earlier actions.
+///
+/// `table_names` is `None` when the statement can't be parsed. Its tables are
+/// unknown then, so a rule whose table the statement text mentions is treated
+/// as an error instead of being skipped.
+///
+/// All of this has to finish within the rule evaluation budget, a rule still
+/// running when it runs out is treated as an error.
+#[allow(clippy::too_many_arguments)]
+#[tracing::instrument(skip(rules, explainer, upstream, timeouts, headers))]
+pub async fn evaluate(
+    rules: &RuleConfig,
+    explainer: &Explainer,
+    upstream: &UpstreamSettings,
+    timeouts: &TimeoutSettings,
+    query: &str,
+    kind: StatementKind,
+    table_names: Option<&HashSet<String>>,
+    client: &Client,
+    headers: &mut HeaderMap<HeaderValue>,
+) -> Decision {
+    let mut decision = Decision::default();
+    let deadline = tokio::time::Instant::now() + timeouts.rule_evaluation();
+
+    for rule in rules.rules.iter() {
+        // if the table doesn't match the rule, we can skip the check. The
+        // table of StatementKind rules is a pattern matched against the
+        // objects the statement changes, which aren't all table references
+        let matches_table = match (&rule.value, table_names) {
+            (RuleContainer::StatementKind(_), _) => true,
+            (_, Some(table_names)) => table_names.contains(&rule.table_name),
+            (_, None) => mentions_table(query, &rule.table_name),
+        };
+        if !matches_table {
+            decision.record(rule, RuleOutcome::NotApplicable);
+            continue;
+        }
+
+        if !rule.users.is_empty() && !matches!(&client.user, Some(u) if rule.users.contains(u)) {
+            decision.record(rule, RuleOutcome::NotApplicable);
+            continue;
+        }
+
+        // a statement that can't be parsed may reference the rule's table,
+        // and neither its kind nor the rule's check can be trusted, so it
+        // fails closed. StatementKind rules fall back to its leading keyword
+        if table_names.is_none() && !matches!(rule.value, RuleContainer::StatementKind(_)) {
+            warn!(
+                "Could not parse a statement that may reference {}",
+                rule.table_name
+            );
+            decision.record(
+                rule,
+                RuleOutcome::Error {
+                    message: format!(
+                        "the statement could not be parsed and may reference {}",
+                        rule.table_name
+                    ),
+                },
+            );
+            decision.verdict = Verdict::Error {
+                rule: rule.name.clone(),
+                message: "Could not parse the statement".to_string(),
+            };
+            return decision;
+        }
+
+        if !rule.statement_kinds.is_empty() && !rule.statement_kinds.contains(&kind) {
+            decision.record(rule, RuleOutcome::NotApplicable);
+            continue;
+        }
+
+        // cost rules only make sense for statements that have a plan, DDL,
+        // SHOW, SET SESSION and the like are never explained
+        if matches!(rule.value, RuleContainer::ScanEstimates(_)) && !kind.is_explainable() {
+            decision.record(rule, RuleOutcome::NotApplicable);
+            continue;
+        }
+
+        let qd = QueryData {
+            query: query.to_string(),
+            headers: headers.clone(),
+            client_ip: client.ip,
+        };
+
+        // marshalling logic betwen the config and evaluating the rule, only
+        // ScanEstimates rules report on unknown estimates
+        let check = async {
+            match &rule.value {
+                RuleContainer::CidrOrigin(cidr) => {
+                    inbound_cidr_check(&rule.table_name, cidr, &rules.networks, &qd)
+                        .await
+                        .map(|violated| (violated, None))
+                }
+                RuleContainer::WhereClause(where_clause) => {
+                    check_for_predicate(&where_clause.filter, &rule.table_name, &qd)
+                        .await
+                        .map(|violated| (violated, None))
+                }
+                RuleContainer::SelectStarNoLimit(_ss) => {
+                    require_limit_if_select_star(&rule.table_name, &qd)
+                        .map(|violated| (violated, None))
+                }
+                RuleContainer::StatementKind(statement_kind) => {
+                    statement_kind_check(&rule.table_name, statement_kind, &qd)
+                        .map(|violated| (violated, None))
+                }
+                RuleContainer::ScanEstimates(cost) => {
+                    scan_estimates_check(
+                        &rule.table_name,
+                        cost,
+                        explainer,
+                        upstream,
+                        client.user.as_deref(),
+                        &qd,
+                    )
+                    .await
+                }
+            }
+        };
+        let res = match tokio::time::timeout_at(deadline, check).await {
+            Ok(res) => res,
+            Err(_) => Err(anyhow!(
+                "rule evaluation timed out after {:?}",
+                timeouts.rule_evaluation()
+            )),
+        };
+
+        match res {
+            Ok((false, unknown_estimates)) => {
+                debug!("No violation of rule: {}", rule.name);
+                decision.record_with_estimates(rule, RuleOutcome::Passed, unknown_estimates);
+            }
+            Ok((true, unknown_estimates)) => {
+                debug!("Violation of rule!: {}", rule.name);
+                decision.record_with_estimates(rule, RuleOutcome::Violated, unknown_estimates);
+                decision.matched.push(RuleMatch {
+                    rule: rule.name.clone(),
+                    priority: rule.priority,
+                });
+
+                for action in rule.actions.iter() {
+                    match apply_action(action, headers) {
+                        Ok(ActionState::Applied) => {
+                            debug!(
+                                "Successfully applied action {:?} for rule: {}",
+                                action, rule.name
+                            );
+                            decision.actions.push(AppliedAction {
+                                rule: rule.name.clone(),
+                                action: action.clone(),
+                            });
+                        }
+                        Ok(ActionState::Blocked(message)) => {
+                            decision.actions.push(AppliedAction {
+                                rule: rule.name.clone(),
+                                action: action.clone(),
+                            });
+                            decision.verdict = Verdict::Block {
+                                rule: rule.name.clone(),
+                                message,
+                            };
+                            return decision;
+                        }
+                        Err(e) => {
+                            warn!("Error applying action: {}", e);
+                            decision.verdict = Verdict::Error {
+                                rule: rule.name.clone(),
+                                message: "Error applying action".to_string(),
+                            };
+                            return decision;
+                        }
+                    }
+                }
+
+                if rule.stop_on_match {
+                    debug!(
+                        "Rule {} has stop_on_match set, skipping the rest",
+                        rule.name
+                    );
+                    break;
+                }
+            }
+            Err(e) => {
+                warn!("Error checking rule: {}", e);
+                decision.record(
+                    rule,
+                    RuleOutcome::Error {
+                        message: e.to_string(),
+                    },
+                );
+                decision.verdict = Verdict::Error {
+                    rule: rule.name.clone(),
+                    message: "Error checking rule".to_string(),
+                };
+                return decision;
+            }
+        }
+    }
+
+    decision
+}
+
+/// Whether the text of a statement mentions a table, by its unqualified name
+/// as the statement may leave the catalog and schema to the session.
+fn mentions_table(query: &str, table_name: &str) -> bool {
+    let table = table_name
+        .rsplit('.')
+        .next()
+        .unwrap_or(table_name)
+        .to_lowercase();
+    let query = query.to_lowercase();
+    let is_word = |c: char| c.is_alphanumeric() || c == '_';
+    if !table.chars().all(is_word) {
+        return query.contains(&table);
+    }
+    query.split(|c: char| !is_word(c)).any(|word| word == table)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use super::*;
+    use crate::cfg::{AuthSettings, ServerSettings};
+
+    fn guarded(table_name: &str) -> RuleConfig {
+        let rule = serde_json::from_value(serde_json::json!({
+            "name": "guard",
+            "table_name": table_name,
+            "value": { "type": "SelectStarNoLimit" },
+            "actions": [{ "type": "Block", "message": "add a limit" }],
+        }))
+        .unwrap();
+        RuleConfig {
+            rules: vec![rule],
+            networks: HashMap::new(),
+        }
+    }
+
+    async fn decide(rules: &RuleConfig, query: &str) -> Decision {
+        let server = ServerSettings::default();
+        let explainer = Explainer::new(&server, &AuthSettings::default()).unwrap();
+        let table_names = crate::rules::extract_tables_from_query(query).ok();
+        evaluate(
+            rules,
+            &explainer,
+            &UpstreamSettings::default(),
+            &server.timeouts,
+            query,
+            StatementKind::of(query),
+            table_names.as_ref(),
+            &Client::default(),
+            &mut HeaderMap::new(),
+        )
+        .await
+    }
+
+    #[tokio::test]
+    async fn blocks_parsed_query_on_guarded_table() {
+        let decision = decide(&guarded("hive.db.t"), "SELECT * FROM hive.db.t").await;
+        assert!(matches!(decision.verdict, Verdict::Block { .. }));
+    }
+
+    #[tokio::test]
+    async fn rejects_unparseable_query_on_guarded_table() {
+        let rules = guarded("hive.db.t");
+        for query in ["SELECT * FROM hive.db.t WHERE )", "SELECT * FROM T WHERE )"] {
+            let decision = decide(&rules, query).await;
+            assert!(
+                matches!(decision.verdict, Verdict::Error { .. }),
+                "{}",
+                query
+            );
+            assert!(matches!(
+                decision.evaluated[0].outcome,
+                RuleOutcome::Error { .. }
+            ));
+        }
+    }
+
+    #[test]
+    fn mentions_tables_by_unqualified_name() {
+        assert!(mentions_table("SHOW STATS FOR Orders", "hive.sales.orders"));
+        assert!(mentions_table(
+            r#"SELECT * FROM "orders""#,
+            "hive.sales.orders"
+        ));
+        assert!(!mentions_table(
+            "SELECT * FROM orders_2024",
+            "hive.sales.orders"
+        ));
+        assert!(mentions_table(
+            "SELECT * FROM \"my-table\"",
+            "hive.sales.my-table"
+        ));
+    }
+
+    #[tokio::test]
+    async fn skips_unparseable_query_on_other_tables() {
+        let decision = decide(&guarded("hive.db.t"), "SELECT * FROM hive.db.s WHERE )").await;
+        assert!(matches!(decision.verdict, Verdict::Allow));
+        assert!(matches!(
+            decision.evaluated[0].outcome,
+            RuleOutcome::NotApplicable
+        ));
+    }
+}
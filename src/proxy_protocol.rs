@@ -0,0 +1,262 @@
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+
+use anyhow::{anyhow, Result};
+
+use tokio::io::{AsyncRead, AsyncReadExt};
+
+const V1_PREFIX: &[u8] = b"PROXY";
+const V2_SIGNATURE: &[u8] = b"\r\n\r\n\0\r\nQUIT\n";
+/// The longest possible v1 header, including the trailing CRLF.
+const V1_MAX_LENGTH: usize = 107;
+
+/// Consume the PROXY protocol (v1 or v2) header at the start of a connection,
+/// returning the client address it announces.
+pub async fn read_proxy_header<S: AsyncRead + Unpin>(
+    stream: &mut S,
+    peer: SocketAddr,
+) -> Result<SocketAddr> {
+    let mut prefix = [0u8; 5];
+    stream.read_exact(&mut prefix).await?;
+
+    let announced = if prefix == V1_PREFIX {
+        read_v1(stream).await?
+    } else if prefix == V2_SIGNATURE[..5] {
+        read_v2(stream).await?
+    } else {
+        return Err(anyhow!("connection did not start with a PROXY header"));
+    };
+
+    // `UNKNOWN` and `LOCAL` headers (e.g. load balancer health checks) don't
+    // carry an address, the connection is from the peer itself
+    Ok(announced.unwrap_or(peer))
+}
+
+/// Reads the rest of a v1 header, e.g. `PROXY TCP4 1.2.3.4 10.0.0.1 56324 3000\r\n`.
+async fn read_v1<S: AsyncRead + Unpin>(stream: &mut S) -> Result<Option<SocketAddr>> {
+    // read a byte at a time so nothing past the header is consumed
+    let mut line = V1_PREFIX.to_vec();
+    while !line.ends_with(b"\r\n") {
+        if line.len() >= V1_MAX_LENGTH {
+            return Err(anyhow!("v1 PROXY header is too long"));
+        }
+        line.push(stream.read_u8().await?);
+    }
+
+    let line = std::str::from_utf8(&line[..line.len() - 2])?;
+    let fields = line.split(' ').collect::<Vec<&str>>();
+
+    match fields[..] {
+        [_, "UNKNOWN", ..] => Ok(None),
+        [_, "TCP4" | "TCP6", src, _dst, src_port, _dst_port] => {
+            let ip = src.parse::<IpAddr>()?;
+            Ok(Some(SocketAddr::new(ip, src_port.parse()?)))
+        }
+        _ => Err(anyhow!("malformed v1 PROXY header: {:?}", line)),
+    }
+}
+
+/// Reads the rest of a binary v2 header, the first 5 bytes of the signature
+/// having already been consumed.
+async fn read_v2<S: AsyncRead + Unpin>(stream: &mut S) -> Result<Option<SocketAddr>> {
+    let mut header = [0u8; 11];
+    stream.read_exact(&mut header).await?;
+
+    if header[..7] != V2_SIGNATURE[5..] {
+        return Err(anyhow!("invalid v2 PROXY signature"));
+    }
+
+    let version_command = header[7];
+    let family = header[8] >> 4;
+    let len = u16::from_be_bytes([header[9], header[10]]) as usize;
+
+    if version_command >> 4 != 2 {
+        return Err(anyhow!("unsupported PROXY protocol version"));
+    }
+
+    let mut addresses = vec![0u8; len];
+    stream.read_exact(&mut addresses).await?;
+
+    // LOCAL connections are made by the proxy itself
+    if version_command & 0x0f == 0 {
+        return Ok(None);
+    }
+
+    match family {
+        // AF_INET: 4 byte source and destination addresses, then the ports
+        1 if len >= 12 => {
+            let ip = Ipv4Addr::new(addresses[0], addresses[1], addresses[2], addresses[3]);
+            let port = u16::from_be_bytes([addresses[8], addresses[9]]);
+            Ok(Some(SocketAddr::new(IpAddr::V4(ip), port)))
+        }
+        // AF_INET6: 16 byte source and destination addresses, then the ports
+        2 if len >= 36 => {
+            let mut octets = [0u8; 16];
+            octets.copy_from_slice(&addresses[..16]);
+            let port = u16::from_be_bytes([addresses[32], addresses[33]]);
+            Ok(Some(SocketAddr::new(
+                IpAddr::V6(Ipv6Addr::from(octets)),
+                port,
+            )))
+        }
+        // AF_UNSPEC and AF_UNIX don't carry an IP address
+        0 | 3 => Ok(None),
+        _ => Err(anyhow!("malformed v2 PROXY header")),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn peer() -> SocketAddr {
+        "10.0.0.1:4000".parse().unwrap()
+    }
+
+    async fn read(mut header: &[u8]) -> Result<SocketAddr> {
+        read_proxy_header(&mut header, peer()).await
+    }
+
+    fn v2(version_command: u8, family_protocol: u8, addresses: &[u8]) -> Vec<u8> {
+        let mut header = V2_SIGNATURE.to_vec();
+        header.push(version_command);
+        header.push(family_protocol);
+        header.extend_from_slice(&(addresses.len() as u16).to_be_bytes());
+        header.extend_from_slice(addresses);
+        header
+    }
+
+    #[tokio::test]
+    async fn v1_tcp4() {
+        let addr = read(b"PROXY TCP4 1.2.3.4 10.0.0.1 56324 3000\r\n").await;
+        assert_eq!(addr.unwrap(), "1.2.3.4:56324".parse().unwrap());
+    }
+
+    #[tokio::test]
+    async fn v1_tcp6() {
+        let addr = read(b"PROXY TCP6 2001:db8::1 2001:db8::2 56324 3000\r\n").await;
+        assert_eq!(addr.unwrap(), "[2001:db8::1]:56324".parse().unwrap());
+    }
+
+    #[tokio::test]
+    async fn v1_unknown() {
+        let addr = read(b"PROXY UNKNOWN\r\n").await;
+        assert_eq!(addr.unwrap(), peer());
+        let addr = read(b"PROXY UNKNOWN ffff::1 ffff::2 1 2\r\n").await;
+        assert_eq!(addr.unwrap(), peer());
+    }
+
+    #[tokio::test]
+    async fn v1_leaves_the_rest_of_the_stream() {
+        let mut stream: &[u8] = b"PROXY TCP4 1.2.3.4 10.0.0.1 56324 3000\r\nGET / HTTP/1.1\r\n";
+        read_proxy_header(&mut stream, peer()).await.unwrap();
+        assert_eq!(stream, b"GET / HTTP/1.1\r\n");
+    }
+
+    #[tokio::test]
+    async fn v1_malformed() {
+        assert!(read(b"PROXY TCP4 1.2.3.4 10.0.0.1 56324\r\n")
+            .await
+            .is_err());
+        assert!(read(b"PROXY TCP4 not-an-ip 10.0.0.1 56324 3000\r\n")
+            .await
+            .is_err());
+        assert!(read(b"PROXY TCP4 1.2.3.4 10.0.0.1 99999 3000\r\n")
+            .await
+            .is_err());
+    }
+
+    #[tokio::test]
+    async fn v1_truncated() {
+        assert!(read(b"PROXY TCP4 1.2.3.4 10.0.0.1").await.is_err());
+        assert!(read(b"PRO").await.is_err());
+    }
+
+    #[tokio::test]
+    async fn v1_too_long() {
+        let mut header = b"PROXY TCP4 ".to_vec();
+        header.resize(header.len() + V1_MAX_LENGTH, b'1');
+        header.extend_from_slice(b"\r\n");
+        assert!(read(&header).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn v2_inet() {
+        let header = v2(
+            0x21,
+            0x11,
+            &[1, 2, 3, 4, 10, 0, 0, 1, 0xdc, 0x04, 0x0b, 0xb8],
+        );
+        let addr = read(&header).await;
+        assert_eq!(addr.unwrap(), "1.2.3.4:56324".parse().unwrap());
+    }
+
+    #[tokio::test]
+    async fn v2_inet6() {
+        let src: Ipv6Addr = "2001:db8::1".parse().unwrap();
+        let dst: Ipv6Addr = "2001:db8::2".parse().unwrap();
+        let mut addresses = src.octets().to_vec();
+        addresses.extend_from_slice(&dst.octets());
+        addresses.extend_from_slice(&[0xdc, 0x04, 0x0b, 0xb8]);
+        let addr = read(&v2(0x21, 0x21, &addresses)).await;
+        assert_eq!(addr.unwrap(), "[2001:db8::1]:56324".parse().unwrap());
+    }
+
+    #[tokio::test]
+    async fn v2_local() {
+        let header = v2(
+            0x20,
+            0x11,
+            &[1, 2, 3, 4, 10, 0, 0, 1, 0xdc, 0x04, 0x0b, 0xb8],
+        );
+        assert_eq!(read(&header).await.unwrap(), peer());
+        assert_eq!(read(&v2(0x20, 0x00, &[])).await.unwrap(), peer());
+    }
+
+    #[tokio::test]
+    async fn v2_unspec() {
+        assert_eq!(read(&v2(0x21, 0x00, &[])).await.unwrap(), peer());
+    }
+
+    #[tokio::test]
+    async fn v2_skips_tlvs_and_leaves_the_rest_of_the_stream() {
+        let addresses = [
+            1, 2, 3, 4, 10, 0, 0, 1, 0xdc, 0x04, 0x0b, 0xb8, 0x04, 0x00, 0x00,
+        ];
+        let mut header = v2(0x21, 0x11, &addresses);
+        header.extend_from_slice(b"GET");
+        let mut stream = &header[..];
+        let addr = read_proxy_header(&mut stream, peer()).await;
+        assert_eq!(addr.unwrap(), "1.2.3.4:56324".parse().unwrap());
+        assert_eq!(stream, b"GET");
+    }
+
+    #[tokio::test]
+    async fn v2_addresses_too_short_for_family() {
+        assert!(read(&v2(0x21, 0x11, &[1, 2, 3, 4])).await.is_err());
+        assert!(read(&v2(0x21, 0x21, &[0; 12])).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn v2_truncated() {
+        let header = v2(
+            0x21,
+            0x11,
+            &[1, 2, 3, 4, 10, 0, 0, 1, 0xdc, 0x04, 0x0b, 0xb8],
+        );
+        assert!(read(&header[..header.len() - 1]).await.is_err());
+        assert!(read(&header[..10]).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn v2_unsupported_version() {
+        assert!(read(&v2(0x11, 0x11, &[0; 12])).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn bad_signature() {
+        assert!(read(b"GET / HTTP/1.1\r\n").await.is_err());
+        let mut header = v2(0x21, 0x11, &[0; 12]);
+        header[6] = b'X';
+        assert!(read(&header).await.is_err());
+    }
+}
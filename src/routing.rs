@@ -0,0 +1,350 @@
+use std::collections::{BTreeMap, BTreeSet, HashSet};
+use std::fmt;
+use std::future::Future;
+
+use anyhow::Result;
+
+use http::HeaderMap;
+
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+
+use tracing::warn;
+
+use crate::cfg::DEFAULT_UPSTREAM;
+use crate::explain::PlanCost;
+use crate::policy::Client;
+
+const SOURCE_HEADER: &str = "x-trino-source";
+const CLIENT_TAGS_HEADER: &str = "x-trino-client-tags";
+const CATALOG_HEADER: &str = "x-trino-catalog";
+const SCHEMA_HEADER: &str = "x-trino-schema";
+
+/// Which upstream new statements are sent to: the first route whose
+/// conditions all match, or the `default` upstream if none does.
+#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema)]
+#[serde(default, deny_unknown_fields)]
+pub struct RoutingSettings {
+    pub routes: Vec<Route>,
+    /// The upstream of statements no route matches.
+    pub default: String,
+}
+
+impl Default for RoutingSettings {
+    fn default() -> Self {
+        RoutingSettings {
+            routes: vec![],
+            default: DEFAULT_UPSTREAM.to_string(),
+        }
+    }
+}
+
+/// A route matches when every condition that is set matches, and a condition
+/// matches when any of its values does.
+#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema)]
+#[serde(deny_unknown_fields)]
+pub struct Route {
+    pub name: String,
+    pub upstream: String,
+    /// Patterns of the tables the query references, e.g. `hive_eu.*`, where
+    /// `*` matches anything. Unqualified table names are qualified with the
+    /// `X-Trino-Catalog` and `X-Trino-Schema` of the request.
+    #[serde(default)]
+    pub tables: Vec<String>,
+    #[serde(default)]
+    pub users: Vec<String>,
+    /// Values of `X-Trino-Source`, e.g. `airflow`.
+    #[serde(default)]
+    pub sources: Vec<String>,
+    /// Tags in `X-Trino-Client-Tags`, e.g. `dashboard`.
+    #[serde(default)]
+    pub client_tags: Vec<String>,
+    /// Bounds on the estimated cost of the query, from an `EXPLAIN`.
+    #[serde(default)]
+    pub cost: Option<CostThresholds>,
+}
+
+/// Upper bounds on the estimated cost of a query, to route queries by size,
+/// e.g. small queries to an interactive cluster and everything else to a
+/// batch cluster. Every bound that is set must hold, and a cost Trino has no
+/// estimate for never does.
+#[derive(Debug, Clone, Default, Serialize, Deserialize, JsonSchema)]
+#[serde(default, deny_unknown_fields)]
+pub struct CostThresholds {
+    pub max_cpu_cost: Option<f64>,
+    pub max_memory_cost: Option<f64>,
+    pub max_network_cost: Option<f64>,
+    /// The estimated size of the query's output.
+    pub max_output_size_in_bytes: Option<f64>,
+}
+
+impl CostThresholds {
+    /// The thresholds paired with the cost they bound, by name.
+    pub fn bounds(&self) -> [(&'static str, Option<f64>); 4] {
+        [
+            ("max_cpu_cost", self.max_cpu_cost),
+            ("max_memory_cost", self.max_memory_cost),
+            ("max_network_cost", self.max_network_cost),
+            ("max_output_size_in_bytes", self.max_output_size_in_bytes),
+        ]
+    }
+
+    fn allows(&self, cost: &PlanCost) -> bool {
+        let within = |max: Option<f64>, estimate: Option<f64>| match (max, estimate) {
+            (None, _) => true,
+            (Some(max), Some(estimate)) => estimate <= max,
+            (Some(_), None) => false,
+        };
+        within(self.max_cpu_cost, cost.cpu_cost)
+            && within(self.max_memory_cost, cost.memory_cost)
+            && within(self.max_network_cost, cost.network_cost)
+            && within(self.max_output_size_in_bytes, cost.output_size_in_bytes)
+    }
+}
+
+impl Route {
+    pub fn has_conditions(&self) -> bool {
+        !(self.tables.is_empty()
+            && self.users.is_empty()
+            && self.sources.is_empty()
+            && self.client_tags.is_empty()
+            && self.cost.is_none())
+    }
+
+    fn matches_table(&self, table: &str) -> bool {
+        self.tables.iter().any(|p| matches_pattern(p, table))
+    }
+
+    /// Whether every condition but the cost matches.
+    fn matches(&self, request: &RoutingRequest) -> bool {
+        (self.tables.is_empty() || request.tables.iter().any(|t| self.matches_table(t)))
+            && (self.users.is_empty()
+                || matches!(request.user, Some(u) if self.users.iter().any(|v| v == u)))
+            && (self.sources.is_empty()
+                || matches!(request.source, Some(s) if self.sources.iter().any(|v| v == s)))
+            && (self.client_tags.is_empty()
+                || request
+                    .client_tags
+                    .iter()
+                    .any(|t| self.client_tags.iter().any(|v| v == t)))
+    }
+}
+
+/// What a statement is routed on.
+struct RoutingRequest<'a> {
+    /// Qualified and lowercased.
+    tables: BTreeSet<String>,
+    user: Option<&'a str>,
+    source: Option<&'a str>,
+    client_tags: Vec<&'a str>,
+}
+
+/// Where a statement was routed, as recorded in the audit log.
+#[derive(Debug, Clone, Serialize)]
+pub struct RouteDecision {
+    /// The route that matched, unset when the statement fell through to the default.
+    pub route: Option<String>,
+    pub upstream: String,
+    /// The estimated cost of the query, if a route needed it.
+    pub cost: Option<PlanCost>,
+}
+
+/// The tables of a query live on more than one upstream, so no single cluster
+/// can run it.
+#[derive(Debug)]
+pub struct SpansUpstreams {
+    /// The upstream of each table routed by a table pattern.
+    pub tables: BTreeMap<String, String>,
+}
+
+impl fmt::Display for SpansUpstreams {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let tables = self
+            .tables
+            .iter()
+            .map(|(table, upstream)| format!("{} ({})", table, upstream))
+            .collect::<Vec<String>>()
+            .join(", ");
+        write!(
+            f,
+            "the query references tables on different upstreams: {}",
+            tables
+        )
+    }
+}
+
+impl RoutingSettings {
+    /// Pick the upstream of a new statement. Routes are tried in order, but
+    /// a query whose tables match table patterns of routes to different
+    /// upstreams is rejected, whichever route would match first.
+    ///
+    /// `plan_cost` is only called, once, when a route with `cost` bounds is
+    /// otherwise a match. If it fails, routes with `cost` bounds don't match.
+    pub async fn route<F, Fut>(
+        &self,
+        tables: &HashSet<String>,
+        client: &Client,
+        headers: &HeaderMap,
+        plan_cost: F,
+    ) -> Result<RouteDecision, SpansUpstreams>
+    where
+        F: FnOnce() -> Fut,
+        Fut: Future<Output = Result<PlanCost>>,
+    {
+        let header = |name: &str| headers.get(name).and_then(|v| v.to_str().ok());
+        let request = RoutingRequest {
+            tables: tables.iter().map(|t| qualify(t, headers)).collect(),
+            user: client.user.as_deref(),
+            source: header(SOURCE_HEADER),
+            client_tags: header(CLIENT_TAGS_HEADER)
+                .map(|tags| tags.split(',').map(|t| t.trim()).collect())
+                .unwrap_or_default(),
+        };
+
+        // each table lives on the upstream of the first route whose patterns match it
+        let homes = request
+            .tables
+            .iter()
+            .filter_map(|table| {
+                let route = self.routes.iter().find(|r| r.matches_table(table))?;
+                Some((table.clone(), route.upstream.clone()))
+            })
+            .collect::<BTreeMap<String, String>>();
+        if homes.values().collect::<HashSet<&String>>().len() > 1 {
+            return Err(SpansUpstreams { tables: homes });
+        }
+
+        let mut plan_cost = Some(plan_cost);
+        let mut cost = None;
+        for route in self.routes.iter().filter(|r| r.matches(&request)) {
+            let Some(thresholds) = &route.cost else {
+                return Ok(RouteDecision {
+                    route: Some(route.name.clone()),
+                    upstream: route.upstream.clone(),
+                    cost,
+                });
+            };
+
+            if let Some(plan_cost) = plan_cost.take() {
+                cost = match plan_cost().await {
+                    Ok(cost) => Some(cost),
+                    Err(e) => {
+                        warn!("Could not estimate the cost of the query: {}", e);
+                        None
+                    }
+                };
+            }
+            if matches!(&cost, Some(cost) if thresholds.allows(cost)) {
+                return Ok(RouteDecision {
+                    route: Some(route.name.clone()),
+                    upstream: route.upstream.clone(),
+                    cost,
+                });
+            }
+        }
+
+        Ok(RouteDecision {
+            route: None,
+            upstream: self.default.clone(),
+            cost,
+        })
+    }
+}
+
+/// Qualify a table name with the `X-Trino-Catalog` and `X-Trino-Schema` of a
+/// request where it lacks them, e.g. `orders` becomes `hive.sales.orders`.
+pub(crate) fn qualify(table: &str, headers: &HeaderMap) -> String {
+    let header = |name: &str| headers.get(name).and_then(|v| v.to_str().ok());
+    let (catalog, schema) = (header(CATALOG_HEADER), header(SCHEMA_HEADER));
+    let qualified = match (table.matches('.').count(), catalog, schema) {
+        (0, Some(catalog), Some(schema)) => format!("{}.{}.{}", catalog, schema, table),
+        (1, Some(catalog), _) => format!("{}.{}", catalog, table),
+        _ => table.to_string(),
+    };
+    qualified.to_lowercase()
+}
+
+/// Whether `name` matches `pattern`, where `*` matches any run of characters.
+/// Compared case insensitively, like Trino identifiers.
+pub(crate) fn matches_pattern(pattern: &str, name: &str) -> bool {
+    let pattern = pattern.to_lowercase();
+    let name = name.to_lowercase();
+
+    let mut parts = pattern.split('*');
+    let first = parts.next().unwrap_or_default();
+    let Some(mut rest) = name.strip_prefix(first) else {
+        return false;
+    };
+    let parts = parts.collect::<Vec<&str>>();
+    let Some((last, middle)) = parts.split_last() else {
+        // no wildcard
+        return rest.is_empty();
+    };
+    for part in middle {
+        match rest.find(part) {
+            Some(i) => rest = &rest[i + part.len()..],
+            None => return false,
+        }
+    }
+    rest.ends_with(last)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn pattern_without_wildcard() {
+        assert!(matches_pattern("hive.db.t", "hive.db.t"));
+        assert!(!matches_pattern("hive.db.t", "hive.db.t2"));
+        assert!(!matches_pattern("hive.db.t", "hive.db"));
+        assert!(!matches_pattern("", "t"));
+        assert!(matches_pattern("", ""));
+    }
+
+    #[test]
+    fn pattern_is_case_insensitive() {
+        assert!(matches_pattern("Hive.DB.*", "hive.db.T"));
+        assert!(matches_pattern("hive.db.t", "HIVE.DB.T"));
+    }
+
+    #[test]
+    fn bare_wildcard_matches_anything() {
+        assert!(matches_pattern("*", "hive.db.t"));
+        assert!(matches_pattern("*", ""));
+        assert!(matches_pattern("**", "t"));
+    }
+
+    #[test]
+    fn trailing_wildcard() {
+        assert!(matches_pattern("hive.*", "hive.db.t"));
+        assert!(matches_pattern("hive.*", "hive."));
+        assert!(!matches_pattern("hive.*", "hive"));
+        assert!(!matches_pattern("hive.*", "hive_eu.db.t"));
+    }
+
+    #[test]
+    fn leading_wildcard() {
+        assert!(matches_pattern("*.events", "hive.db.events"));
+        assert!(!matches_pattern("*.events", "hive.db.events_2"));
+    }
+
+    #[test]
+    fn middle_wildcards() {
+        assert!(matches_pattern("hive.*.events", "hive.db.events"));
+        assert!(matches_pattern("hive.*.events", "hive..events"));
+        assert!(!matches_pattern("hive.*.events", "iceberg.db.events"));
+        assert!(matches_pattern("a*b*c", "abc"));
+        assert!(matches_pattern("a*b*c", "axxbyyc"));
+        assert!(!matches_pattern("a*b*c", "acb"));
+    }
+
+    #[test]
+    fn prefix_and_suffix_do_not_overlap() {
+        assert!(!matches_pattern("ab*ba", "aba"));
+        assert!(matches_pattern("ab*ba", "abba"));
+        assert!(!matches_pattern("a*a", "a"));
+        assert!(!matches_pattern("a*b*b", "ab"));
+        assert!(matches_pattern("a*b*b", "abb"));
+    }
+}
@@ -1,20 +1,15 @@
 use anyhow::anyhow;
-use std::collections::HashSet;
+use std::collections::{BTreeSet, HashMap, HashSet};
 use std::fmt::Debug;
-use std::net::Ipv4Addr;
-use std::time::Duration;
-
-use axum::http::header;
+use std::net::IpAddr;
 
 use derive_visitor::{visitor_enter_fn, Drive};
 
 use http::HeaderMap;
 use http::HeaderValue;
 
-use cidr_utils::cidr::Ipv4Cidr;
-
 use log::info;
-use log::warn;
+use schemars::JsonSchema;
 use serde::Serialize;
 
 use sqlparser::ast::SelectItem;
@@ -29,103 +24,274 @@ use serde::Deserialize;
 use anyhow::Result;
 use tracing::debug;
 use tracing::error;
+use tracing::warn;
 
-const ALLOWED_HEADERS: &[header::HeaderName] = &[
-    header::CONTENT_LENGTH,
-    header::CONTENT_TYPE,
-    header::IF_MATCH,
-    header::RANGE,
-];
+use crate::actions::{ActionConfig, ActionType};
+use crate::cfg::UpstreamSettings;
+use crate::explain::{Estimate, ExplainNode, Explainer, FloatingPointHack, TableStats};
+use crate::networks::{NetworkRef, NetworkSet};
+use crate::routing::{matches_pattern, qualify};
+use crate::statement::{Operation, StatementKind};
 
 /// The structs in this section are mainly for parsing the rules from a config file.
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Serialize, Deserialize, JsonSchema)]
 pub struct WhereClauseRule {
+    /// A SQL expression AST, as serialized by sqlparser.
+    #[schemars(with = "serde_json::Value")]
     pub filter: Expr,
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Serialize, Deserialize, JsonSchema)]
 pub struct SelectStarRule {
     // #TODO: add limit min/max
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+/// Clients must come from one of the `allow` networks (any network if the list
+/// is empty), and not from any of the `deny` networks.
+#[derive(Debug, Serialize, Deserialize, JsonSchema)]
 pub struct CidrOriginRule {
-    pub inbound_cidr: Ipv4Cidr,
+    #[serde(default, alias = "inbound_cidr", deserialize_with = "one_or_many")]
+    #[schemars(with = "OneOrMany<NetworkRef>")]
+    pub allow: Vec<NetworkRef>,
+    #[serde(default)]
+    pub deny: Vec<NetworkRef>,
+}
+
+/// Violated by statements that perform any of the `deny` operations on an
+/// object matching the rule's `table_name`, a pattern where `*` matches
+/// anything, e.g. `hive.*`.
+#[derive(Debug, Serialize, Deserialize, JsonSchema)]
+pub struct StatementKindRule {
+    pub deny: Vec<Operation>,
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+/// Runs an `EXPLAIN` of the query, and is violated when any of the thresholds
+/// is exceeded or the plan has a shape that is denied.
+#[derive(Debug, Serialize, Deserialize, JsonSchema)]
 pub struct CostInput {
-    pub max_cpu_cost: f64,
+    /// Shorthand for `max_per_node.cpu_cost`.
+    #[serde(default)]
+    pub max_cpu_cost: Option<f64>,
+    /// Thresholds on the largest estimate of any single plan node.
+    #[serde(default)]
+    pub max_per_node: EstimateThresholds,
+    /// Thresholds on the estimates of the whole plan: the sum of the row
+    /// counts and sizes of every node, and the cumulative costs of the plan.
+    #[serde(default)]
+    pub max_total: EstimateThresholds,
+    /// Violated by any cross join in the plan.
+    #[serde(default)]
+    pub deny_cross_joins: bool,
+    /// Violated by a scan of the rule's table that has no filter pushed down
+    /// into the connector.
+    #[serde(default)]
+    pub deny_unfiltered_scans: bool,
+    /// What to do when an estimate with a threshold is unknown.
+    #[serde(default)]
+    pub unknown_estimates: UnknownEstimates,
+}
+
+/// How a `ScanEstimates` rule treats estimates Trino has no statistics for,
+/// which it reports as `NaN`.
+#[derive(Debug, Clone, Default, Serialize, Deserialize, JsonSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum UnknownEstimates {
+    /// Skip the unknown estimates, only the known ones are checked.
+    #[default]
+    Ignore,
+    /// Any unknown estimate violates the rule.
+    Violation,
+    /// Fall back to the size of every table scanned by the plan, from
+    /// `SHOW STATS`. A table over a limit or without a row count violates
+    /// the rule, and so does a plan none of whose scanned tables is known.
+    TableSize(TableSizeLimits),
+}
+
+impl UnknownEstimates {
+    fn name(&self) -> &'static str {
+        match self {
+            UnknownEstimates::Ignore => "ignore",
+            UnknownEstimates::Violation => "violation",
+            UnknownEstimates::TableSize(_) => "table_size",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize, JsonSchema)]
+#[serde(default, deny_unknown_fields)]
+pub struct TableSizeLimits {
+    pub max_rows: Option<f64>,
+    pub max_bytes: Option<f64>,
+}
+
+/// How a `ScanEstimates` rule dealt with unknown estimates, as recorded in the
+/// audit log.
+#[derive(Debug, Serialize)]
+pub struct UnknownEstimatesReport {
+    /// The estimates with a threshold that were unknown, e.g. `max_total.cpu_cost`.
+    pub unknown: Vec<String>,
+    pub policy: &'static str,
+    /// For the `table_size` policy, the tables over a limit or without a row
+    /// count, with their statistics.
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub tables: Vec<String>,
+    /// Whether the unknown estimates violated the rule.
+    pub violated: bool,
+}
+
+impl CostInput {
+    /// The per-node thresholds, with `max_cpu_cost` applied.
+    pub fn per_node(&self) -> EstimateThresholds {
+        EstimateThresholds {
+            cpu_cost: self.max_per_node.cpu_cost.or(self.max_cpu_cost),
+            ..self.max_per_node.clone()
+        }
+    }
+}
+
+/// A threshold for each of the estimates of an `EXPLAIN`, any of them optional.
+#[derive(Debug, Clone, Default, Serialize, Deserialize, JsonSchema)]
+#[serde(default, deny_unknown_fields)]
+pub struct EstimateThresholds {
+    pub output_row_count: Option<f64>,
+    pub output_size_in_bytes: Option<f64>,
+    pub cpu_cost: Option<f64>,
+    pub memory_cost: Option<f64>,
+    pub network_cost: Option<f64>,
+}
+
+impl TableSizeLimits {
+    /// Whether the table is within the limits. A table without a row count
+    /// never is, nor is one without a data size when `max_bytes` is set.
+    fn allows(&self, stats: &TableStats) -> bool {
+        let within = |max: Option<f64>, value: Option<f64>| match (max, value) {
+            (None, _) => true,
+            (Some(max), Some(value)) => value <= max,
+            (Some(_), None) => false,
+        };
+        stats.row_count.is_some()
+            && within(self.max_rows, stats.row_count)
+            && within(self.max_bytes, stats.data_size)
+    }
+}
+
+impl EstimateThresholds {
+    /// Every threshold, named like the estimate of an `EXPLAIN` it bounds.
+    pub fn bounds(&self) -> [(&'static str, Option<f64>); 5] {
+        [
+            ("output_row_count", self.output_row_count),
+            ("output_size_in_bytes", self.output_size_in_bytes),
+            ("cpu_cost", self.cpu_cost),
+            ("memory_cost", self.memory_cost),
+            ("network_cost", self.network_cost),
+        ]
+    }
 }
 
 /// As new Rule architypes are added, this enum is to be extended as it
 /// controls how config is parsed and any input parameters to the rule.
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Serialize, Deserialize, JsonSchema)]
 #[serde(tag = "type")]
 pub enum RuleContainer {
     WhereClause(WhereClauseRule),
     SelectStarNoLimit(SelectStarRule),
     CidrOrigin(CidrOriginRule),
     ScanEstimates(CostInput),
+    StatementKind(StatementKindRule),
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Serialize, Deserialize, JsonSchema)]
+#[serde(deny_unknown_fields)]
 pub struct RuleEntry {
     pub name: String,
     pub table_name: String,
     pub value: RuleContainer,
-    pub action: Option<ActionType>,
+    /// Only apply the rule to these users, or to everyone if empty.
+    #[serde(default)]
+    pub users: Vec<String>,
+    /// Only apply the rule to these kinds of statements, or to every kind if empty.
+    #[serde(default)]
+    pub statement_kinds: Vec<StatementKind>,
+    /// Rules with a higher priority are evaluated first, ties keep file order.
+    #[serde(default)]
+    pub priority: i32,
+    /// Skip the remaining rules once this one has matched and its actions ran.
+    #[serde(default)]
+    pub stop_on_match: bool,
+    /// Actions applied in order when the rule is violated. The legacy
+    /// singular `action` key is still accepted.
+    #[serde(default, alias = "action", deserialize_with = "action_chain")]
+    #[schemars(with = "OneOrMany<ActionConfig>")]
+    pub actions: Vec<ActionType>,
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+/// The rules as evaluated by the proxy, taken from the settings with
+/// [`Settings::take_rules`](crate::cfg::Settings::take_rules).
+#[derive(Debug)]
 pub struct RuleConfig {
     pub rules: Vec<RuleEntry>,
+    /// Named network sets that `CidrOrigin` rules can refer to.
+    pub networks: HashMap<String, NetworkSet>,
 }
 
-#[derive(Debug, Serialize, Deserialize)]
-pub enum ActionType {
-    Block,
-    InjectHeader,
+impl RuleConfig {
+    /// Order the rules for evaluation, highest priority first. The sort is
+    /// stable so rules of equal priority keep the order of the config file.
+    pub fn sort_by_priority(&mut self) {
+        self.rules.sort_by_key(|r| std::cmp::Reverse(r.priority));
+    }
+
+    /// Load the files backing the network sets.
+    pub fn load_networks(&self) -> Result<()> {
+        for (name, set) in self.networks.iter() {
+            set.reload()
+                .map_err(|e| anyhow!("could not load network set {}: {}", name, e))?;
+        }
+        Ok(())
+    }
 }
 
-// This enum is used to decouple the state of Action application failure vs. blocking.
-pub enum ActionState {
-    Applied,
+#[derive(Deserialize, JsonSchema)]
+#[serde(untagged)]
+enum OneOrMany<T> {
+    One(T),
+    Many(Vec<T>),
 }
 
-pub fn send_query_to_low_priority(headers: &mut HeaderMap<HeaderValue>) -> Result<ActionState> {
-    let prev_value = headers
-        .get(header::HeaderName::from_static("x-trino-client-tags"))
-        .map(|v| v.to_str().expect("Could not convert header value to str"));
-
-    // split the value on commas and add lowprio to the list
-    let new_value = match prev_value {
-        Some(v) => {
-            let mut values = v.split(',').collect::<Vec<&str>>();
-            values.push("lowprio");
-            values.join(",")
-        }
-        None => "lowprio".to_string(),
-    };
+fn one_or_many<'de, D, T>(deserializer: D) -> Result<Vec<T>, D::Error>
+where
+    D: serde::Deserializer<'de>,
+    T: Deserialize<'de>,
+{
+    Ok(match Option::<OneOrMany<T>>::deserialize(deserializer)? {
+        Some(OneOrMany::One(v)) => vec![v],
+        Some(OneOrMany::Many(v)) => v,
+        None => vec![],
+    })
+}
 
-    headers.insert(
-        header::HeaderName::from_static("x-trino-client-tags"),
-        header::HeaderValue::from_str(&new_value).expect("invalid header value"),
-    );
-    Ok(ActionState::Applied)
+fn action_chain<'de, D>(deserializer: D) -> Result<Vec<ActionType>, D::Error>
+where
+    D: serde::Deserializer<'de>,
+{
+    let actions = one_or_many::<D, ActionConfig>(deserializer)?;
+    Ok(actions.into_iter().map(ActionType::from).collect())
 }
 
 #[derive(Debug, Clone)]
 pub struct QueryData {
     pub query: String,
     pub headers: HeaderMap<HeaderValue>,
+    /// The client address, resolved from the forwarding headers and TCP peer.
+    pub client_ip: Option<IpAddr>,
 }
 
 #[tracing::instrument]
 pub async fn inbound_cidr_check(
     input_table_name: &str,
-    input_cidr: Ipv4Cidr,
+    rule: &CidrOriginRule,
+    networks: &HashMap<String, NetworkSet>,
     data: &QueryData,
 ) -> Result<bool> {
     let mut found_table = false;
@@ -154,28 +320,40 @@ pub async fn inbound_cidr_check(
         return Ok(false);
     }
 
-    let ip = data
-        .headers
-        .get(header::HeaderName::from_static("x-forwarded-for"))
-        .map(|v| v.to_str().expect("Could not convert header value to str"));
-
-    match ip {
-        Some(ip) => {
-            let ip = ip.parse::<Ipv4Addr>().expect("Could not parse IP address");
-            if input_cidr.contains(ip) {
-                debug!("IP {} is in CIDR {}", ip, input_cidr);
-                Ok(false)
-            } else {
-                debug!("IP {} is NOT in CIDR {}", ip, input_cidr);
-                Ok(true)
-            }
+    let Some(ip) = data.client_ip else {
+        debug!("Could not resolve the client IP address");
+        // we will consider this case a violation, something is wrong
+        return Ok(true);
+    };
+
+    let contains = |network: &NetworkRef| match network {
+        NetworkRef::Cidr(cidr) => Ok(cidr.contains(ip)),
+        NetworkRef::Named(name) => networks
+            .get(name)
+            .map(|set| set.contains(ip))
+            .ok_or_else(|| anyhow!("undefined network set {}", name)),
+    };
+
+    for network in rule.deny.iter() {
+        if contains(network)? {
+            debug!("IP {} is in denied network {:?}", ip, network);
+            return Ok(true);
         }
-        None => {
-            debug!("No IP address found in headers");
-            // we will consider this case a violation, something is wrong
-            Ok(true)
+    }
+
+    if rule.allow.is_empty() {
+        return Ok(false);
+    }
+
+    for network in rule.allow.iter() {
+        if contains(network)? {
+            debug!("IP {} is in allowed network {:?}", ip, network);
+            return Ok(false);
         }
     }
+
+    debug!("IP {} is NOT in any allowed network", ip);
+    Ok(true)
 }
 
 // // Generic "Rule" implementation that searches for a WHERE clause in a query
@@ -222,81 +400,251 @@ pub async fn check_for_predicate(
     Ok(!found_predicate && found_table)
 }
 
-// write the scan_estimates_check function
-#[tracing::instrument]
+/// Return value of true means the plan exceeds a threshold or has a denied
+/// shape. When estimates with a threshold are unknown, the report of how the
+/// rule's policy for them was applied is returned along with it.
+#[tracing::instrument(skip(explainer))]
 pub async fn scan_estimates_check(
     input_table_name: &str,
-    max_cpu_cost: f32,
+    rule: &CostInput,
+    explainer: &Explainer,
+    upstream: &UpstreamSettings,
+    user: Option<&str>,
     data: &QueryData,
-) -> Result<bool> {
-    // ceck if the authorization header is set here
-    if !data
-        .headers
-        .contains_key(header::HeaderName::from_static("authorization"))
-    {
-        // throw error because we can't run this check w/o user auth
+) -> Result<(bool, Option<UnknownEstimatesReport>)> {
+    // we can't run this check w/o credentials to run the EXPLAIN with
+    if !explainer.has_credentials(&data.headers) {
         return Err(anyhow!(
             "Authorization header not set, we can't run this rule!"
         ));
     }
 
-    let mut http_client = reqwest::ClientBuilder::new();
-
-    let mut retained_headers = data.headers.clone();
-    ALLOWED_HEADERS.iter().for_each(|h| {
-        retained_headers.remove(h);
-    });
-
-    // set default headers
-    http_client = http_client
-        .default_headers(retained_headers)
-        .timeout(Duration::from_secs(30));
-
-    let client = trino::Client {
-        base_url: format!(
-            "https://{}",
-            std::env::var("STARPROXY_UPSTREAM_URL").expect("STARPROXY_UPSTREAM_URL not set")
-        ),
-        port: 443,
-        user: None,
-        http_client: http_client.build().expect("Failed to make http client"),
+    info!("explaining query: {}", data.query);
+    let graph = match explainer
+        .explain(upstream, &data.headers, user, &data.query)
+        .await
+    {
+        Ok(graph) => graph,
+        Err(e) => {
+            error!("Error: {:?}", e);
+            return Ok((false, None));
+        }
     };
+    info!("res: {:?}", graph);
+
+    // Trino's costs are cumulative, every node's include those of its inputs,
+    // so the largest of each is both the per-node maximum and the cost of the
+    // whole plan. Summing them would count every subtree again for each node
+    // above it. Nodes without an estimate leave the largest known one to be
+    // checked, the dimension is unknown as well.
+    let mut per_node_max = HashMap::new();
+    let mut totals = HashMap::new();
+    let mut unknown = HashSet::new();
+    let cumulative: [(&str, fn(&Estimate) -> &FloatingPointHack); 3] = [
+        ("cpu_cost", |e| &e.cpu_cost),
+        ("memory_cost", |e| &e.memory_cost),
+        ("network_cost", |e| &e.network_cost),
+    ];
+    for (dimension, estimate) in cumulative {
+        let (max, partial) = graph.max_known(estimate);
+        if let Some(max) = max {
+            per_node_max.insert(dimension, max);
+            totals.insert(dimension, max);
+        }
+        if partial {
+            unknown.insert(dimension);
+        }
+    }
 
-    use crate::explain::ExplainNode;
-    let explain_query = format!("EXPLAIN (TYPE LOGICAL, FORMAT JSON) {}", data.query.clone());
-
-    info!("explain_query: {}", explain_query);
-    let res = client.query::<Vec<String>>(&explain_query).await;
-
-    match res {
-        Ok(graph) => {
-            let graph: ExplainNode =
-                serde_json::from_str(&graph[0][0]).expect("Could not parse explain node");
-            info!("res: {:?}", graph);
-
-            // recursively search the graph for the max_cpu_cost
-            let mut max_cpu_cost_found = 0.0;
-            graph.drive(&mut visitor_enter_fn(|node: &ExplainNode| {
-                if let Some(estimates) = &node.estimates {
-                    for estimate in estimates {
-                        if let crate::explain::FloatingPointHack::Float(cost) = estimate.cpu_cost {
-                            if cost > max_cpu_cost_found {
-                                max_cpu_cost_found = cost;
-                            }
-                        }
+    // recursively search the graph for the per-node maxima and totals of the
+    // row counts and sizes, which are per node, and denied plan shapes
+    let mut scanned_tables = BTreeSet::new();
+    let mut cross_joins = 0;
+    let mut unfiltered_scans = 0;
+    graph.drive(&mut visitor_enter_fn(|node: &ExplainNode| {
+        for estimate in node.estimates.iter().flatten() {
+            let outputs = [
+                ("output_row_count", &estimate.output_row_count),
+                ("output_size_in_bytes", &estimate.output_size_in_bytes),
+            ];
+            for (dimension, value) in outputs {
+                match value.value() {
+                    Some(value) => {
+                        let max = per_node_max.entry(dimension).or_insert(0.0);
+                        *max = value.max(*max);
+                        *totals.entry(dimension).or_insert(0.0) += value;
+                    }
+                    None => {
+                        unknown.insert(dimension);
                     }
                 }
-            }));
+            }
+        }
+        if node.name == "CrossJoin" {
+            cross_joins += 1;
+        }
+        if is_unfiltered_scan(node, input_table_name) {
+            unfiltered_scans += 1;
+        }
+        scanned_tables.extend(scanned_table(node));
+    }));
+    info!(
+        "per node maxima: {:?}, totals: {:?}, unknown: {:?}, cross joins: {}, unfiltered scans of {}: {}",
+        per_node_max, totals, unknown, cross_joins, input_table_name, unfiltered_scans
+    );
 
-            info!("max_cpu_cost_found: {}", max_cpu_cost_found);
+    let exceeded = |found: &HashMap<&str, f64>, thresholds: &EstimateThresholds| {
+        thresholds.bounds().into_iter().any(|(dimension, max)| {
+            matches!((max, found.get(dimension)), (Some(max), Some(&v)) if v > max)
+        })
+    };
+    let violated = exceeded(&per_node_max, &rule.per_node())
+        || exceeded(&totals, &rule.max_total)
+        || (rule.deny_cross_joins && cross_joins > 0)
+        || (rule.deny_unfiltered_scans && unfiltered_scans > 0);
+
+    // only the unknown estimates that have a threshold matter
+    let thresholds = [
+        ("max_per_node", rule.per_node()),
+        ("max_total", rule.max_total.clone()),
+    ];
+    let unknown = thresholds
+        .iter()
+        .flat_map(|(key, thresholds)| {
+            thresholds
+                .bounds()
+                .into_iter()
+                .filter(|(dimension, max)| max.is_some() && unknown.contains(dimension))
+                .map(move |(dimension, _)| format!("{}.{}", key, dimension))
+        })
+        .collect::<Vec<String>>();
+    if unknown.is_empty() {
+        return Ok((violated, None));
+    }
 
-            if max_cpu_cost_found > max_cpu_cost {
-                return Ok(true);
+    let (unknown_violated, tables) = match &rule.unknown_estimates {
+        UnknownEstimates::Ignore => (false, vec![]),
+        UnknownEstimates::Violation => (true, vec![]),
+        // with no table to check the size of, nothing shows the query is small
+        UnknownEstimates::TableSize(_) if scanned_tables.is_empty() => {
+            warn!("No scanned table found in the plan to check the size of");
+            (true, vec![])
+        }
+        UnknownEstimates::TableSize(limits) => {
+            let mut oversized = vec![];
+            for table in scanned_tables.iter() {
+                match explainer
+                    .table_stats(upstream, &data.headers, user, table)
+                    .await
+                {
+                    Ok(stats) if limits.allows(&stats) => {}
+                    Ok(stats) => oversized.push(format!(
+                        "{} (rows: {:?}, bytes: {:?})",
+                        table, stats.row_count, stats.data_size
+                    )),
+                    Err(e) => oversized.push(format!("{} ({})", table, e)),
+                }
             }
+            (!oversized.is_empty(), oversized)
         }
+    };
+    debug!(
+        "unknown estimates {:?} handled by policy {}, violated: {}",
+        unknown,
+        rule.unknown_estimates.name(),
+        unknown_violated
+    );
+
+    let report = UnknownEstimatesReport {
+        unknown,
+        policy: rule.unknown_estimates.name(),
+        tables,
+        violated: unknown_violated,
+    };
+    Ok((violated || unknown_violated, Some(report)))
+}
+
+/// The table handle of a scan, from the `table` of its descriptor, or a
+/// `table = ...` detail in older versions of Trino. Either may be followed
+/// by details of the handle, such as the pushed down constraint.
+fn table_handle(node: &ExplainNode) -> Option<&str> {
+    node.descriptor
+        .table
+        .as_deref()
+        .or_else(|| node.details.iter().find_map(|d| d.strip_prefix("table = ")))
+}
+
+/// The table a node scans, e.g. `hive:sales:orders`. Handles that name the
+/// table as `catalog:schema.table`, like Iceberg's, are normalized to that,
+/// without suffixes such as a snapshot id.
+fn scanned_table(node: &ExplainNode) -> Option<String> {
+    let handle = table_handle(node)?.split_whitespace().next()?;
+    let handle = handle.trim_end_matches(',').split(['$', '@']).next()?;
+    let (catalog, name) = handle.split_once(':')?;
+    let (schema, table) = name.split_once(':').or_else(|| name.split_once('.'))?;
+    Some(format!("{}:{}:{}", catalog, schema, table))
+}
+
+/// Whether the node scans `table_name` without a filter pushed down into the
+/// connector. Scans of filtered tables are either fused with the filter
+/// (`ScanFilter`, `ScanFilterProject`), or list the pushed down constraint or
+/// column domains in their details.
+fn is_unfiltered_scan(node: &ExplainNode, table_name: &str) -> bool {
+    if node.name != "TableScan" {
+        return false;
+    }
+
+    let scanned = scanned_table(node).map(|t| t.replace(':', ".").to_lowercase());
+    let table_name = table_name.to_lowercase();
+    let is_table = matches!(
+        scanned,
+        Some(scanned) if scanned == table_name || scanned.ends_with(&format!(".{}", table_name))
+    );
+
+    is_table
+        && !table_handle(node)
+            .into_iter()
+            .chain(node.details.iter().map(|d| d.as_str()))
+            .any(|d| d.contains("constraint on") || d.contains(":: ["))
+}
+
+/// Return value of true means the statement performs a denied operation on an
+/// object matching the table pattern. Object names are matched as written, and
+/// qualified with the session catalog and schema.
+#[tracing::instrument]
+pub fn statement_kind_check(
+    table_pattern: &str,
+    rule: &StatementKindRule,
+    data: &QueryData,
+) -> Result<bool> {
+    let ast = match SQLParser::parse_sql(&MySqlDialect {}, &data.query) {
+        Ok(ast) => ast,
         Err(e) => {
-            error!("Error: {:?}", e);
-            return Ok(false);
+            // the objects of a statement that can't be parsed are unknown, so
+            // one that looks like a denied operation violates the rule
+            let operation = Operation::of_keyword(&data.query);
+            debug!(
+                "Could not parse the statement ({}), looks like {:?}",
+                e, operation
+            );
+            return Ok(matches!(operation, Some(operation) if rule.deny.contains(&operation)));
+        }
+    };
+
+    for statement in ast.iter() {
+        let Some((operation, objects)) = Operation::of_statement(statement) else {
+            continue;
+        };
+        if !rule.deny.contains(&operation) {
+            continue;
+        }
+        for object in objects.iter() {
+            if matches_pattern(table_pattern, object)
+                || matches_pattern(table_pattern, &qualify(object, &data.headers))
+            {
+                debug!("Denied operation {:?} on {}", operation, object);
+                return Ok(true);
+            }
         }
     }
 
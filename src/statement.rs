@@ -0,0 +1,329 @@
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+
+use sqlparser::ast::{GrantObjects, ObjectName, Statement, TableFactor};
+use sqlparser::dialect::MySqlDialect;
+use sqlparser::parser::Parser as SQLParser;
+
+/// What a statement does, as classified from its AST.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, JsonSchema)]
+pub enum StatementKind {
+    /// A `SELECT`, `WITH` or `VALUES` query.
+    Query,
+    Insert,
+    /// `CREATE TABLE ... AS SELECT`.
+    CreateTableAs,
+    Update,
+    Delete,
+    /// Statements that change the objects of a catalog or who may use them:
+    /// `CREATE`, `ALTER`, `DROP`, `TRUNCATE`, `GRANT` and `REVOKE`.
+    #[serde(rename = "DDL")]
+    Ddl,
+    /// Everything else, e.g. `SHOW`, `SET SESSION`, `USE`, `CALL` and
+    /// `EXPLAIN`, as well as input that isn't a single statement that parses.
+    Other,
+}
+
+impl StatementKind {
+    /// Classify the statement of a request.
+    pub fn of(query: &str) -> Self {
+        match SQLParser::parse_sql(&MySqlDialect {}, query).as_deref() {
+            Ok([statement]) => StatementKind::of_statement(statement),
+            _ => StatementKind::Other,
+        }
+    }
+
+    pub fn of_statement(statement: &Statement) -> Self {
+        match statement {
+            Statement::Query(_) => StatementKind::Query,
+            Statement::Insert { .. } => StatementKind::Insert,
+            Statement::CreateTable { query: Some(_), .. } => StatementKind::CreateTableAs,
+            Statement::Update { .. } => StatementKind::Update,
+            Statement::Delete { .. } => StatementKind::Delete,
+            Statement::CreateTable { .. }
+            | Statement::CreateView { .. }
+            | Statement::CreateSchema { .. }
+            | Statement::CreateDatabase { .. }
+            | Statement::CreateIndex { .. }
+            | Statement::AlterTable { .. }
+            | Statement::Drop { .. }
+            | Statement::Truncate { .. }
+            | Statement::Grant { .. }
+            | Statement::Revoke { .. } => StatementKind::Ddl,
+            _ => StatementKind::Other,
+        }
+    }
+
+    /// Whether the statement has a plan worth an `EXPLAIN`, i.e. it reads or
+    /// writes table data. Only these are explained by rules and routes.
+    pub fn is_explainable(&self) -> bool {
+        matches!(
+            self,
+            StatementKind::Query | StatementKind::Insert | StatementKind::CreateTableAs
+        )
+    }
+}
+
+/// A change to a catalog that `StatementKind` rules can deny.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, JsonSchema)]
+pub enum Operation {
+    /// `DROP` of a table, view, index or schema.
+    Drop,
+    /// `ALTER TABLE`.
+    Alter,
+    /// `DELETE` without a `WHERE` clause, which empties the table.
+    DeleteWithoutWhere,
+    Truncate,
+    Grant,
+    Revoke,
+    /// `CREATE TABLE ... AS SELECT`.
+    CreateTableAs,
+    Insert,
+}
+
+impl Operation {
+    /// The operation a statement performs, with the names of the objects it
+    /// performs it on. `None` for statements that are none of the operations.
+    pub fn of_statement(statement: &Statement) -> Option<(Operation, Vec<String>)> {
+        let (operation, objects) = match statement {
+            Statement::Drop { names, .. } => (Operation::Drop, names.iter().collect()),
+            Statement::AlterTable { name, .. } => (Operation::Alter, vec![name]),
+            Statement::Delete {
+                table_name: TableFactor::Table { name, .. },
+                selection: None,
+                ..
+            } => (Operation::DeleteWithoutWhere, vec![name]),
+            Statement::Truncate { table_name, .. } => (Operation::Truncate, vec![table_name]),
+            Statement::Grant { objects, .. } => (Operation::Grant, grant_objects(objects)),
+            Statement::Revoke { objects, .. } => (Operation::Revoke, grant_objects(objects)),
+            Statement::CreateTable {
+                name,
+                query: Some(_),
+                ..
+            } => (Operation::CreateTableAs, vec![name]),
+            Statement::Insert { table_name, .. } => (Operation::Insert, vec![table_name]),
+            _ => return None,
+        };
+        Some((operation, objects.into_iter().map(object_name).collect()))
+    }
+
+    /// The operation a statement that can't be parsed looks like it performs,
+    /// from its leading keyword. A `CREATE` is only taken for a `CREATE TABLE
+    /// ... AS` when it has an `AS`, and a `DELETE` is assumed to have no `WHERE`.
+    pub fn of_keyword(query: &str) -> Option<Operation> {
+        let query = skip_comments(query);
+        let mut words = query.split_whitespace().map(|w| w.to_uppercase());
+        let operation = match words.next()?.as_str() {
+            "DROP" => Operation::Drop,
+            "ALTER" => Operation::Alter,
+            "DELETE" => Operation::DeleteWithoutWhere,
+            "TRUNCATE" => Operation::Truncate,
+            "GRANT" => Operation::Grant,
+            "REVOKE" => Operation::Revoke,
+            "INSERT" => Operation::Insert,
+            "CREATE" if words.any(|w| w == "AS") => Operation::CreateTableAs,
+            _ => return None,
+        };
+        Some(operation)
+    }
+}
+
+/// The statement after any leading whitespace and comments.
+fn skip_comments(mut query: &str) -> &str {
+    loop {
+        query = query.trim_start();
+        if let Some(rest) = query.strip_prefix("--") {
+            query = rest.split_once('\n').map_or("", |(_, rest)| rest);
+        } else if let Some(rest) = query.strip_prefix("/*") {
+            query = rest.split_once("*/").map_or("", |(_, rest)| rest);
+        } else {
+            return query;
+        }
+    }
+}
+
+/// The tables, schemas or sequences privileges are granted on or revoked from.
+fn grant_objects(objects: &GrantObjects) -> Vec<&ObjectName> {
+    match objects {
+        GrantObjects::Tables(names)
+        | GrantObjects::Schemas(names)
+        | GrantObjects::Sequences(names) => names.iter().collect(),
+        GrantObjects::AllTablesInSchema { schemas }
+        | GrantObjects::AllSequencesInSchema { schemas } => schemas.iter().collect(),
+    }
+}
+
+fn object_name(ObjectName(parts): &ObjectName) -> String {
+    parts
+        .iter()
+        .map(|x| x.value.to_owned())
+        .collect::<Vec<String>>()
+        .join(".")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(query: &str) -> Statement {
+        let mut statements = SQLParser::parse_sql(&MySqlDialect {}, query).unwrap();
+        assert_eq!(statements.len(), 1);
+        statements.remove(0)
+    }
+
+    fn operation(query: &str) -> Option<(Operation, Vec<String>)> {
+        Operation::of_statement(&parse(query))
+    }
+
+    fn names(names: &[&str]) -> Vec<String> {
+        names.iter().map(|n| n.to_string()).collect()
+    }
+
+    #[test]
+    fn kinds() {
+        let cases = [
+            ("SELECT * FROM t", StatementKind::Query),
+            ("WITH x AS (SELECT 1) SELECT * FROM x", StatementKind::Query),
+            ("INSERT INTO t VALUES (1)", StatementKind::Insert),
+            (
+                "CREATE TABLE t AS SELECT * FROM s",
+                StatementKind::CreateTableAs,
+            ),
+            ("UPDATE t SET a = 1 WHERE b = 2", StatementKind::Update),
+            ("DELETE FROM t WHERE a = 1", StatementKind::Delete),
+            ("DELETE FROM t", StatementKind::Delete),
+            ("CREATE TABLE t (a INT)", StatementKind::Ddl),
+            ("CREATE VIEW v AS SELECT * FROM t", StatementKind::Ddl),
+            ("ALTER TABLE t ADD COLUMN b INT", StatementKind::Ddl),
+            ("DROP TABLE t", StatementKind::Ddl),
+            ("TRUNCATE TABLE t", StatementKind::Ddl),
+            ("GRANT SELECT ON t TO alice", StatementKind::Ddl),
+            ("REVOKE SELECT ON t FROM alice", StatementKind::Ddl),
+            ("SHOW TABLES", StatementKind::Other),
+        ];
+        for (query, kind) in cases {
+            assert_eq!(StatementKind::of(query), kind, "{}", query);
+        }
+    }
+
+    #[test]
+    fn kind_of_anything_but_a_single_statement() {
+        assert_eq!(
+            StatementKind::of("SELECT 1; DROP TABLE t"),
+            StatementKind::Other
+        );
+        assert_eq!(StatementKind::of("SELEC * FROM"), StatementKind::Other);
+        assert_eq!(StatementKind::of(""), StatementKind::Other);
+    }
+
+    #[test]
+    fn explainable_kinds() {
+        assert!(StatementKind::Query.is_explainable());
+        assert!(StatementKind::Insert.is_explainable());
+        assert!(StatementKind::CreateTableAs.is_explainable());
+        assert!(!StatementKind::Update.is_explainable());
+        assert!(!StatementKind::Ddl.is_explainable());
+        assert!(!StatementKind::Other.is_explainable());
+    }
+
+    #[test]
+    fn operations() {
+        let cases = [
+            (
+                "DROP TABLE hive.db.t",
+                Operation::Drop,
+                names(&["hive.db.t"]),
+            ),
+            ("DROP TABLE a, b", Operation::Drop, names(&["a", "b"])),
+            (
+                "ALTER TABLE db.t ADD COLUMN b INT",
+                Operation::Alter,
+                names(&["db.t"]),
+            ),
+            (
+                "DELETE FROM db.t",
+                Operation::DeleteWithoutWhere,
+                names(&["db.t"]),
+            ),
+            ("TRUNCATE TABLE t", Operation::Truncate, names(&["t"])),
+            (
+                "GRANT SELECT ON a, b TO alice",
+                Operation::Grant,
+                names(&["a", "b"]),
+            ),
+            (
+                "GRANT SELECT ON ALL TABLES IN SCHEMA db TO alice",
+                Operation::Grant,
+                names(&["db"]),
+            ),
+            (
+                "REVOKE SELECT ON t FROM alice",
+                Operation::Revoke,
+                names(&["t"]),
+            ),
+            (
+                "CREATE TABLE db.t AS SELECT * FROM s",
+                Operation::CreateTableAs,
+                names(&["db.t"]),
+            ),
+            (
+                "INSERT INTO db.t SELECT * FROM s",
+                Operation::Insert,
+                names(&["db.t"]),
+            ),
+        ];
+        for (query, op, objects) in cases {
+            assert_eq!(operation(query), Some((op, objects)), "{}", query);
+        }
+    }
+
+    #[test]
+    fn statements_without_an_operation() {
+        for query in [
+            "SELECT * FROM t",
+            "DELETE FROM t WHERE a = 1",
+            "UPDATE t SET a = 1",
+            "CREATE TABLE t (a INT)",
+        ] {
+            assert_eq!(operation(query), None, "{}", query);
+        }
+    }
+
+    #[test]
+    fn operations_of_keyword() {
+        let cases = [
+            ("DROP TABLE t", Some(Operation::Drop)),
+            ("alter table t", Some(Operation::Alter)),
+            ("DELETE FROM t WHERE", Some(Operation::DeleteWithoutWhere)),
+            ("truncate t", Some(Operation::Truncate)),
+            ("GRANT", Some(Operation::Grant)),
+            ("REVOKE", Some(Operation::Revoke)),
+            ("INSERT INTO t", Some(Operation::Insert)),
+            ("CREATE TABLE t AS SELECT", Some(Operation::CreateTableAs)),
+            ("create table t\nas\nselect", Some(Operation::CreateTableAs)),
+            ("CREATE TABLE t (a INT)", None),
+            ("SELECT * FROM t", None),
+            ("", None),
+        ];
+        for (query, op) in cases {
+            assert_eq!(Operation::of_keyword(query), op, "{:?}", query);
+        }
+    }
+
+    #[test]
+    fn operations_of_keyword_after_comments() {
+        let cases = [
+            ("-- cleanup\nDROP TABLE t", Some(Operation::Drop)),
+            ("/* cleanup */ DROP TABLE t", Some(Operation::Drop)),
+            (
+                "  /* a */ -- b\n /* c */\n\tTRUNCATE t",
+                Some(Operation::Truncate),
+            ),
+            ("/* DROP TABLE t", None),
+            ("-- DROP TABLE t", None),
+        ];
+        for (query, op) in cases {
+            assert_eq!(Operation::of_keyword(query), op, "{:?}", query);
+        }
+    }
+}
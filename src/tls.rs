@@ -0,0 +1,164 @@
+use std::fs::File;
+use std::io::BufReader;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::SystemTime;
+
+use anyhow::{anyhow, Result};
+
+use rustls::server::{
+    AllowAnyAnonymousOrAuthenticatedClient, AllowAnyAuthenticatedClient, ClientHello,
+    ResolvesServerCert,
+};
+use rustls::sign::{any_supported_type, CertifiedKey};
+use rustls::{Certificate, PrivateKey, RootCertStore, ServerConfig};
+
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+
+use tokio_rustls::TlsAcceptor;
+
+use x509_parser::parse_x509_certificate;
+
+use crate::cfg::{AuthSettings, ClientAuth};
+
+/// TLS settings for the listener, PEM files for the certificate chain and its
+/// private key.
+#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema)]
+#[serde(deny_unknown_fields)]
+pub struct TlsSettings {
+    pub cert_path: PathBuf,
+    pub key_path: PathBuf,
+}
+
+/// The identity from a verified client certificate.
+#[derive(Debug, Clone)]
+pub struct ClientCertificate {
+    pub subject: String,
+    pub common_name: Option<String>,
+}
+
+impl ClientCertificate {
+    pub fn from_der(der: &[u8]) -> Result<Self> {
+        let (_, cert) = parse_x509_certificate(der)?;
+        let subject = cert.subject();
+        Ok(ClientCertificate {
+            subject: subject.to_string(),
+            common_name: subject
+                .iter_common_name()
+                .next()
+                .and_then(|cn| cn.as_str().ok())
+                .map(|cn| cn.to_string()),
+        })
+    }
+}
+
+/// Serves the certificate and key from disk, swapping them out when
+/// [`reload`](Self::reload) finds that either file has changed.
+pub struct ReloadingCertResolver {
+    cert_path: PathBuf,
+    key_path: PathBuf,
+    key: RwLock<Arc<CertifiedKey>>,
+    modified: Mutex<(SystemTime, SystemTime)>,
+}
+
+impl ReloadingCertResolver {
+    pub fn load(cert_path: &Path, key_path: &Path) -> Result<Self> {
+        Ok(ReloadingCertResolver {
+            cert_path: cert_path.to_path_buf(),
+            key_path: key_path.to_path_buf(),
+            key: RwLock::new(Arc::new(load_certified_key(cert_path, key_path)?)),
+            modified: Mutex::new(modified_times(cert_path, key_path)?),
+        })
+    }
+
+    /// Reload the certificate and key if either file changed since they were
+    /// last loaded. Returns whether they were reloaded; on error the current
+    /// certificate stays in use.
+    pub fn reload(&self) -> Result<bool> {
+        let modified = modified_times(&self.cert_path, &self.key_path)?;
+        let mut last_modified = self.modified.lock().expect("cert lock poisoned");
+        if *last_modified == modified {
+            return Ok(false);
+        }
+
+        let key = load_certified_key(&self.cert_path, &self.key_path)?;
+        *self.key.write().expect("cert lock poisoned") = Arc::new(key);
+        *last_modified = modified;
+        Ok(true)
+    }
+}
+
+impl ResolvesServerCert for ReloadingCertResolver {
+    fn resolve(&self, _client_hello: ClientHello) -> Option<Arc<CertifiedKey>> {
+        Some(self.key.read().expect("cert lock poisoned").clone())
+    }
+}
+
+fn modified_times(cert_path: &Path, key_path: &Path) -> Result<(SystemTime, SystemTime)> {
+    Ok((
+        std::fs::metadata(cert_path)?.modified()?,
+        std::fs::metadata(key_path)?.modified()?,
+    ))
+}
+
+pub(crate) fn load_certs(path: &Path) -> Result<Vec<Certificate>> {
+    let certs = rustls_pemfile::certs(&mut BufReader::new(File::open(path)?))?;
+    if certs.is_empty() {
+        return Err(anyhow!("no certificates found in {}", path.display()));
+    }
+    Ok(certs.into_iter().map(Certificate).collect())
+}
+
+fn load_certified_key(cert_path: &Path, key_path: &Path) -> Result<CertifiedKey> {
+    let certs = load_certs(cert_path)?;
+
+    let key = rustls_pemfile::read_all(&mut BufReader::new(File::open(key_path)?))?
+        .into_iter()
+        .find_map(|item| match item {
+            rustls_pemfile::Item::RSAKey(key)
+            | rustls_pemfile::Item::PKCS8Key(key)
+            | rustls_pemfile::Item::ECKey(key) => Some(PrivateKey(key)),
+            _ => None,
+        })
+        .ok_or_else(|| anyhow!("no private key found in {}", key_path.display()))?;
+
+    let signing_key = any_supported_type(&key)
+        .map_err(|_| anyhow!("unsupported private key in {}", key_path.display()))?;
+
+    Ok(CertifiedKey::new(certs, signing_key))
+}
+
+/// Build the acceptor for the listener, returning the cert resolver so the
+/// caller can reload it. Client certificates are verified as configured in `auth`.
+pub fn tls_acceptor(
+    settings: &TlsSettings,
+    auth: &AuthSettings,
+) -> Result<(TlsAcceptor, Arc<ReloadingCertResolver>)> {
+    let resolver = Arc::new(ReloadingCertResolver::load(
+        &settings.cert_path,
+        &settings.key_path,
+    )?);
+
+    let builder = ServerConfig::builder().with_safe_defaults();
+    let builder = match &auth.client_ca_path {
+        Some(ca_path) => {
+            let mut roots = RootCertStore::empty();
+            for cert in load_certs(ca_path)? {
+                roots.add(&cert)?;
+            }
+            if auth.client_auth == ClientAuth::Required {
+                builder.with_client_cert_verifier(AllowAnyAuthenticatedClient::new(roots))
+            } else {
+                builder
+                    .with_client_cert_verifier(AllowAnyAnonymousOrAuthenticatedClient::new(roots))
+            }
+        }
+        None => builder.with_no_client_auth(),
+    };
+
+    let mut config = builder.with_cert_resolver(resolver.clone());
+    config.alpn_protocols = vec![b"http/1.1".to_vec()];
+
+    Ok((TlsAcceptor::from(Arc::new(config)), resolver))
+}
@@ -26,22 +26,29 @@ pub struct QueryResults {
     #[serde(skip_serializing_if = "Option::is_none")]
     pub error: Option<Value>,
 
+    #[serde(default)]
     pub warnings: Vec<Warning>,
 
     #[serde(skip_serializing_if = "Option::is_none")]
     pub update_type: Option<String>,
 
     #[serde(skip_serializing_if = "Option::is_none")]
-    pub update_count: Option<u32>,
+    pub update_count: Option<u64>,
 }
 
 #[derive(Debug, Deserialize, Serialize)]
 pub struct Warning {
     #[serde(rename = "warningCode")]
-    pub code: u32,
+    pub code: WarningCode,
     pub message: String,
 }
 
+#[derive(Debug, Deserialize, Serialize)]
+pub struct WarningCode {
+    pub code: i32,
+    pub name: String,
+}
+
 // stats example data:
 // {"state":"QUEUED","queued":true,"scheduled":false,"nodes":0,"totalSplits":0,"queuedSplits":0,"runningSplits":0,"completedSplits":0,"cpuTimeMillis":0,"wallTimeMillis":0,"queuedTimeMillis":0,"elapsedTimeMillis":0,"processedRows":0,"processedBytes":0,"physicalInputBytes":0,"peakMemoryBytes":0,"spilledBytes":0}
 // make a rust struct for stats
@@ -51,18 +58,18 @@ pub struct Stats {
     pub state: String,
     pub queued: bool,
     pub scheduled: bool,
-    pub nodes: u32,
-    pub total_splits: u32,
-    pub queued_splits: u32,
-    pub running_splits: u32,
-    pub completed_splits: u32,
-    pub cpu_time_millis: u32,
-    pub wall_time_millis: u32,
-    pub queued_time_millis: u32,
-    pub elapsed_time_millis: u32,
-    pub processed_rows: u32,
-    pub processed_bytes: u32,
-    pub physical_input_bytes: u32,
-    pub peak_memory_bytes: u32,
-    pub spilled_bytes: u32,
+    pub nodes: u64,
+    pub total_splits: u64,
+    pub queued_splits: u64,
+    pub running_splits: u64,
+    pub completed_splits: u64,
+    pub cpu_time_millis: u64,
+    pub wall_time_millis: u64,
+    pub queued_time_millis: u64,
+    pub elapsed_time_millis: u64,
+    pub processed_rows: u64,
+    pub processed_bytes: u64,
+    pub physical_input_bytes: u64,
+    pub peak_memory_bytes: u64,
+    pub spilled_bytes: u64,
 }
@@ -0,0 +1,563 @@
+use std::collections::{BTreeMap, HashMap};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::{Duration, Instant};
+
+use anyhow::{anyhow, Result};
+
+use chrono::{DateTime, Utc};
+
+use hyper::client::connect::Connect;
+use hyper::Client;
+
+use serde::{Deserialize, Serialize};
+
+use tracing::{info, warn};
+
+use crate::cfg::{
+    HealthCheckSettings, SelectionStrategy, UpstreamPoolSettings, UpstreamSettings,
+    DEFAULT_UPSTREAM,
+};
+
+/// The weight of a coordinator with no queued queries under
+/// [`SelectionStrategy::WeightedQueued`].
+const MAX_WEIGHT: i64 = 1000;
+
+/// The result of the last health check of a coordinator.
+#[derive(Debug, Clone, Serialize)]
+pub struct CoordinatorStatus {
+    pub available: bool,
+    /// Why the coordinator is unavailable.
+    pub reason: Option<String>,
+    /// From `/v1/cluster`, only fetched for pools that pick coordinators by load.
+    pub running_queries: Option<u64>,
+    pub queued_queries: Option<u64>,
+    pub checked_at: DateTime<Utc>,
+}
+
+/// The part of the coordinator's `/v1/info` the health check cares about.
+#[derive(Debug, Deserialize)]
+struct ServerInfo {
+    starting: bool,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct ClusterStats {
+    running_queries: u64,
+    queued_queries: u64,
+}
+
+#[derive(Debug)]
+pub struct Coordinator {
+    pub settings: UpstreamSettings,
+    status: RwLock<Option<CoordinatorStatus>>,
+    /// Queries sent to this coordinator since its last health check, which
+    /// its stats don't include yet.
+    sent_since_check: AtomicUsize,
+}
+
+impl Coordinator {
+    fn new(settings: UpstreamSettings) -> Self {
+        Coordinator {
+            settings,
+            status: RwLock::new(None),
+            sent_since_check: AtomicUsize::new(0),
+        }
+    }
+
+    pub fn status(&self) -> Option<CoordinatorStatus> {
+        self.status.read().expect("status lock poisoned").clone()
+    }
+
+    /// Record the result of a health check, returning the previous one.
+    fn set_status(&self, status: CoordinatorStatus) -> Option<CoordinatorStatus> {
+        self.status
+            .write()
+            .expect("status lock poisoned")
+            .replace(status)
+    }
+
+    /// Take the coordinator out of rotation until the next health check finds
+    /// it available again.
+    pub fn mark_unavailable(&self, reason: String) {
+        self.set_status(unavailable(reason));
+    }
+
+    /// Whether the last health check found the coordinator available.
+    pub fn is_available(&self) -> bool {
+        matches!(self.status(), Some(status) if status.available)
+    }
+
+    /// Whether new queries may be sent to the coordinator. Coordinators that
+    /// have not been checked yet are given the benefit of the doubt.
+    fn is_selectable(&self) -> bool {
+        !matches!(self.status(), Some(status) if !status.available)
+    }
+
+    /// The queries running or queued on the coordinator as of its last health
+    /// check, from any client, plus the ones sent to it through this proxy since.
+    pub fn outstanding(&self) -> usize {
+        let reported = self
+            .status()
+            .map(|s| s.running_queries.unwrap_or(0) + s.queued_queries.unwrap_or(0))
+            .unwrap_or(0);
+        reported as usize + self.sent_since_check.load(Ordering::SeqCst)
+    }
+}
+
+#[derive(Debug)]
+pub struct Pool {
+    pub name: String,
+    pub strategy: SelectionStrategy,
+    pub coordinators: Vec<Arc<Coordinator>>,
+    pub statement_retries: usize,
+    next: AtomicUsize,
+    /// The running weights of smooth weighted round robin.
+    current_weights: Mutex<Vec<i64>>,
+}
+
+impl Pool {
+    fn new(name: &str, settings: &UpstreamPoolSettings) -> Self {
+        Pool {
+            name: name.to_string(),
+            strategy: settings.strategy,
+            coordinators: settings
+                .coordinators
+                .iter()
+                .map(|c| Arc::new(Coordinator::new(c.clone())))
+                .collect(),
+            statement_retries: settings.statement_retries,
+            next: AtomicUsize::new(0),
+            current_weights: Mutex::new(vec![0; settings.coordinators.len()]),
+        }
+    }
+
+    /// Whether any coordinator of the pool passed its last health check.
+    pub fn is_available(&self) -> bool {
+        self.coordinators.iter().any(|c| c.is_available())
+    }
+
+    /// Pick the coordinator for a new query, other than the ones it was already
+    /// `tried` on, or `None` if every other coordinator is unavailable.
+    pub fn select(&self, tried: &[Arc<Coordinator>]) -> Option<Arc<Coordinator>> {
+        let candidates = (0..self.coordinators.len())
+            .filter(|&i| {
+                let coordinator = &self.coordinators[i];
+                coordinator.is_selectable() && !tried.iter().any(|t| Arc::ptr_eq(t, coordinator))
+            })
+            .collect::<Vec<usize>>();
+        if candidates.is_empty() {
+            return None;
+        }
+
+        let selected = match self.strategy {
+            SelectionStrategy::RoundRobin => {
+                let start = self.next.fetch_add(1, Ordering::SeqCst);
+                candidates[start % candidates.len()]
+            }
+            SelectionStrategy::LeastOutstanding => *candidates
+                .iter()
+                .min_by_key(|&&i| self.coordinators[i].outstanding())
+                .expect("candidates is not empty"),
+            SelectionStrategy::WeightedQueued => self.select_weighted(&candidates),
+        };
+        Some(self.coordinators[selected].clone())
+    }
+
+    /// Smooth weighted round robin: every pick adds each candidate's weight to
+    /// its running weight, takes the candidate with the highest running weight
+    /// and subtracts the total weight from it. Coordinators without stats get
+    /// the lowest weight.
+    fn select_weighted(&self, candidates: &[usize]) -> usize {
+        let weights = candidates
+            .iter()
+            .map(
+                |&i| match self.coordinators[i].status().and_then(|s| s.queued_queries) {
+                    Some(queued) => (MAX_WEIGHT / (1 + queued as i64)).max(1),
+                    None => 1,
+                },
+            )
+            .collect::<Vec<i64>>();
+        let total = weights.iter().sum::<i64>();
+
+        let mut current = self.current_weights.lock().expect("weights lock poisoned");
+        for (&i, weight) in candidates.iter().zip(weights) {
+            current[i] += weight;
+        }
+        let selected = *candidates
+            .iter()
+            .max_by_key(|&&i| (current[i], std::cmp::Reverse(i)))
+            .expect("candidates is not empty");
+        current[selected] -= total;
+        selected
+    }
+
+    /// The coordinator for requests that aren't about a particular query, such
+    /// as the web UI: the first available one, so they all land on the same
+    /// coordinator while it is healthy.
+    pub fn primary(&self) -> Arc<Coordinator> {
+        self.coordinators
+            .iter()
+            .find(|c| c.is_selectable())
+            .unwrap_or(&self.coordinators[0])
+            .clone()
+    }
+}
+
+#[derive(Debug)]
+struct Binding {
+    coordinator: Arc<Coordinator>,
+    last_used: Instant,
+}
+
+/// Every upstream pool, and the coordinator each query in flight is bound to.
+#[derive(Debug)]
+pub struct Upstreams {
+    pools: BTreeMap<String, Pool>,
+    bindings: Mutex<HashMap<String, Binding>>,
+    binding_ttl: Duration,
+}
+
+impl Upstreams {
+    pub fn new(settings: &BTreeMap<String, UpstreamPoolSettings>, binding_ttl: Duration) -> Self {
+        Upstreams {
+            pools: settings
+                .iter()
+                .map(|(name, pool)| (name.clone(), Pool::new(name, pool)))
+                .collect(),
+            bindings: Mutex::new(HashMap::new()),
+            binding_ttl,
+        }
+    }
+
+    pub fn pools(&self) -> impl Iterator<Item = &Pool> {
+        self.pools.values()
+    }
+
+    pub fn pool(&self, name: &str) -> Option<&Pool> {
+        self.pools.get(name)
+    }
+
+    pub fn default_pool(&self) -> &Pool {
+        &self.pools[DEFAULT_UPSTREAM]
+    }
+
+    /// Send every follow-up request for the query to this coordinator.
+    pub fn bind(&self, query_id: &str, coordinator: &Arc<Coordinator>) {
+        let binding = Binding {
+            coordinator: coordinator.clone(),
+            last_used: Instant::now(),
+        };
+        let previous = self
+            .bindings
+            .lock()
+            .expect("bindings lock poisoned")
+            .insert(query_id.to_string(), binding);
+
+        if previous.is_none() {
+            coordinator.sent_since_check.fetch_add(1, Ordering::SeqCst);
+        }
+    }
+
+    /// The coordinator the query is bound to, if it is still bound.
+    pub fn bound(&self, query_id: &str) -> Option<Arc<Coordinator>> {
+        let mut bindings = self.bindings.lock().expect("bindings lock poisoned");
+        let binding = bindings.get_mut(query_id)?;
+        binding.last_used = Instant::now();
+        Some(binding.coordinator.clone())
+    }
+
+    /// The coordinator of the default upstream when it has only one, which
+    /// follow-up requests for queries that aren't bound can go to without
+    /// guessing.
+    pub fn sole_coordinator(&self) -> Option<Arc<Coordinator>> {
+        match &self.default_pool().coordinators[..] {
+            [coordinator] => Some(coordinator.clone()),
+            _ => None,
+        }
+    }
+
+    pub fn unbind(&self, query_id: &str) {
+        self.bindings
+            .lock()
+            .expect("bindings lock poisoned")
+            .remove(query_id);
+    }
+
+    /// Drop the bindings of queries that haven't been requested for longer
+    /// than the binding TTL, which Trino would have abandoned by then.
+    pub fn expire_bindings(&self) {
+        let mut bindings = self.bindings.lock().expect("bindings lock poisoned");
+        bindings.retain(|_, binding| binding.last_used.elapsed() < self.binding_ttl);
+    }
+}
+
+/// The query id in the path of a follow-up request, e.g.
+/// `/v1/statement/executing/20230120_101500_00042_abcde/y5d9.../1`.
+pub fn query_id_from_path(path: &str) -> Option<&str> {
+    let segments = path.trim_start_matches('/').split('/').collect::<Vec<_>>();
+    match segments[..] {
+        ["v1", "statement", "executing", "partialCancel", query_id, ..] => Some(query_id),
+        ["v1", "statement", "queued" | "executing", query_id, ..] => Some(query_id),
+        ["v1", "query", query_id, ..] => Some(query_id),
+        _ => None,
+    }
+}
+
+/// Whether the path is that of a partial cancel, which cancels a stage of a
+/// query, e.g. `/v1/statement/executing/partialCancel/{queryId}/{stage}/...`,
+/// rather than the query itself.
+pub fn is_partial_cancel(path: &str) -> bool {
+    path.trim_start_matches('/')
+        .starts_with("v1/statement/executing/partialCancel/")
+}
+
+/// Check every coordinator of every pool on an interval. A coordinator is
+/// unavailable while its `/v1/info` reports `starting`, or when the check
+/// fails or times out. Pools weighted by queued queries also fetch `/v1/cluster`.
+pub async fn check_upstreams<C>(
+    upstreams: Arc<Upstreams>,
+    client: Client<C>,
+    settings: HealthCheckSettings,
+) where
+    C: Connect + Clone + Send + Sync + 'static,
+{
+    let mut interval = tokio::time::interval(settings.interval());
+    loop {
+        interval.tick().await;
+        for pool in upstreams.pools() {
+            for coordinator in pool.coordinators.iter() {
+                let check = check_coordinator(&client, pool.strategy, &coordinator.settings);
+                let status = match tokio::time::timeout(settings.timeout(), check).await {
+                    Ok(status) => status,
+                    Err(_) => unavailable("the health check timed out".to_string()),
+                };
+
+                // the stats, where fetched, now include the queries sent before the check
+                coordinator.sent_since_check.store(0, Ordering::SeqCst);
+
+                // only log changes, starting with the first check
+                let previous = coordinator.set_status(status.clone());
+                if previous.map(|p| p.available) != Some(status.available) {
+                    let name = coordinator.settings.authority();
+                    match &status.reason {
+                        Some(reason) => {
+                            warn!(
+                                "Upstream {} ({}) is unavailable: {}",
+                                pool.name, name, reason
+                            )
+                        }
+                        None => info!("Upstream {} ({}) is available", pool.name, name),
+                    }
+                }
+            }
+        }
+    }
+}
+
+fn unavailable(reason: String) -> CoordinatorStatus {
+    CoordinatorStatus {
+        available: false,
+        reason: Some(reason),
+        running_queries: None,
+        queued_queries: None,
+        checked_at: Utc::now(),
+    }
+}
+
+async fn check_coordinator<C>(
+    client: &Client<C>,
+    strategy: SelectionStrategy,
+    upstream: &UpstreamSettings,
+) -> CoordinatorStatus
+where
+    C: Connect + Clone + Send + Sync + 'static,
+{
+    match get_json::<C, ServerInfo>(client, upstream, "/v1/info").await {
+        Ok(info) if info.starting => return unavailable("the cluster is starting".to_string()),
+        Ok(_) => {}
+        Err(e) => return unavailable(e.to_string()),
+    }
+
+    // the stats only weigh the coordinator, failing to get them doesn't make it unavailable
+    let stats = match strategy {
+        SelectionStrategy::LeastOutstanding | SelectionStrategy::WeightedQueued => {
+            match get_json::<C, ClusterStats>(client, upstream, "/v1/cluster").await {
+                Ok(stats) => Some(stats),
+                Err(e) => {
+                    warn!(
+                        "Could not get cluster stats of {}: {}",
+                        upstream.authority(),
+                        e
+                    );
+                    None
+                }
+            }
+        }
+        _ => None,
+    };
+
+    CoordinatorStatus {
+        available: true,
+        reason: None,
+        running_queries: stats.as_ref().map(|s| s.running_queries),
+        queued_queries: stats.as_ref().map(|s| s.queued_queries),
+        checked_at: Utc::now(),
+    }
+}
+
+async fn get_json<C, T>(client: &Client<C>, upstream: &UpstreamSettings, path: &str) -> Result<T>
+where
+    C: Connect + Clone + Send + Sync + 'static,
+    T: for<'de> Deserialize<'de>,
+{
+    let res = client.get(upstream.uri(path)?).await?;
+    if !res.status().is_success() {
+        return Err(anyhow!("{} returned {}", path, res.status()));
+    }
+    let body = hyper::body::to_bytes(res.into_body()).await?;
+    Ok(serde_json::from_slice(&body)?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn pool(strategy: SelectionStrategy, coordinators: usize) -> Pool {
+        let settings = UpstreamPoolSettings {
+            coordinators: (0..coordinators)
+                .map(|i| UpstreamSettings {
+                    host: format!("c{}", i),
+                    ..UpstreamSettings::default()
+                })
+                .collect(),
+            strategy,
+            ..UpstreamPoolSettings::default()
+        };
+        Pool::new("test", &settings)
+    }
+
+    fn stats(running: u64, queued: u64) -> CoordinatorStatus {
+        CoordinatorStatus {
+            available: true,
+            reason: None,
+            running_queries: Some(running),
+            queued_queries: Some(queued),
+            checked_at: Utc::now(),
+        }
+    }
+
+    fn host(coordinator: Option<Arc<Coordinator>>) -> String {
+        coordinator
+            .expect("a coordinator is selected")
+            .settings
+            .host
+            .clone()
+    }
+
+    #[test]
+    fn query_ids_from_paths() {
+        let id = "20230120_101500_00042_abcde";
+        let cases = [
+            format!("/v1/statement/queued/{}/y5d9/1", id),
+            format!("/v1/statement/executing/{}/y5d9/1", id),
+            format!("/v1/statement/executing/partialCancel/{}/2/y5d9/1", id),
+            format!("/v1/query/{}", id),
+            format!("/v1/query/{}/killed", id),
+        ];
+        for path in cases {
+            assert_eq!(query_id_from_path(&path), Some(id), "{}", path);
+        }
+    }
+
+    #[test]
+    fn paths_without_query_ids() {
+        for path in ["/v1/statement", "/v1/info", "/ui/", "/", "/v1/query"] {
+            assert_eq!(query_id_from_path(path), None, "{}", path);
+        }
+    }
+
+    #[test]
+    fn partial_cancels() {
+        assert!(is_partial_cancel(
+            "/v1/statement/executing/partialCancel/20230120_101500_00042_abcde/2/y5d9/1"
+        ));
+        assert!(!is_partial_cancel(
+            "/v1/statement/executing/20230120_101500_00042_abcde/y5d9/1"
+        ));
+    }
+
+    #[test]
+    fn round_robin() {
+        let pool = pool(SelectionStrategy::RoundRobin, 3);
+        let picks = (0..6).map(|_| host(pool.select(&[]))).collect::<Vec<_>>();
+        assert_eq!(picks, ["c0", "c1", "c2", "c0", "c1", "c2"]);
+    }
+
+    #[test]
+    fn select_skips_unavailable_and_tried() {
+        let pool = pool(SelectionStrategy::RoundRobin, 3);
+        pool.coordinators[1].mark_unavailable("down".to_string());
+        let tried = [pool.coordinators[0].clone()];
+        for _ in 0..3 {
+            assert_eq!(host(pool.select(&tried)), "c2");
+        }
+        let tried = [pool.coordinators[0].clone(), pool.coordinators[2].clone()];
+        assert!(pool.select(&tried).is_none());
+    }
+
+    #[test]
+    fn least_outstanding() {
+        let pool = pool(SelectionStrategy::LeastOutstanding, 3);
+        pool.coordinators[0].set_status(stats(5, 0));
+        pool.coordinators[1].set_status(stats(1, 2));
+        pool.coordinators[2].set_status(stats(2, 2));
+        assert_eq!(host(pool.select(&[])), "c1");
+
+        // queries sent since the last check count until the next one
+        pool.coordinators[1]
+            .sent_since_check
+            .store(2, Ordering::SeqCst);
+        assert_eq!(host(pool.select(&[])), "c2");
+    }
+
+    #[test]
+    fn weighted_queued_favours_idle_coordinators() {
+        let pool = pool(SelectionStrategy::WeightedQueued, 2);
+        pool.coordinators[0].set_status(stats(0, 0));
+        pool.coordinators[1].set_status(stats(0, 999));
+        let picks = (0..1001)
+            .map(|_| host(pool.select(&[])))
+            .filter(|h| h == "c1")
+            .count();
+        assert_eq!(picks, 1);
+    }
+
+    #[test]
+    fn weighted_queued_without_stats_alternates() {
+        let pool = pool(SelectionStrategy::WeightedQueued, 2);
+        let picks = (0..4).map(|_| host(pool.select(&[]))).collect::<Vec<_>>();
+        assert_eq!(picks, ["c0", "c1", "c0", "c1"]);
+    }
+
+    #[test]
+    fn select_weighted_only_considers_candidates() {
+        let pool = pool(SelectionStrategy::WeightedQueued, 3);
+        pool.coordinators[0].set_status(stats(0, 0));
+        let picks = (0..4)
+            .map(|_| pool.select_weighted(&[1, 2]))
+            .collect::<Vec<_>>();
+        assert_eq!(picks, [1, 2, 1, 2]);
+    }
+
+    #[test]
+    fn primary_is_the_first_selectable() {
+        let pool = pool(SelectionStrategy::RoundRobin, 2);
+        assert_eq!(pool.primary().settings.host, "c0");
+        pool.coordinators[0].mark_unavailable("down".to_string());
+        assert_eq!(pool.primary().settings.host, "c1");
+        pool.coordinators[1].mark_unavailable("down".to_string());
+        assert_eq!(pool.primary().settings.host, "c0");
+    }
+}